@@ -0,0 +1,209 @@
+//! HTTP load generator for a running server, using std only.
+//!
+//! `cargo bench --bench load` keeps `LOAD_CONNECTIONS` keep-alive connections busy sending
+//! `GET LOAD_URL` for `LOAD_DURATION` seconds and prints throughput, latencies and statuses.
+//! `LOAD_TOKEN` is sent as a bearer token if set.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct Settings {
+    address: String,
+    path: String,
+    connections: usize,
+    duration: Duration,
+    token: Option<String>,
+}
+
+impl Settings {
+    fn from_env() -> Result<Self, String> {
+        let url = env::var("LOAD_URL").unwrap_or_else(|_| "http://127.0.0.1:9000/boards".into());
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("only http:// URLs are supported: {}", url))?;
+        let (address, path) = match rest.find('/') {
+            Some(i) => (rest[..i].to_string(), rest[i..].to_string()),
+            None => (rest.to_string(), "/".to_string()),
+        };
+        Ok(Self {
+            address,
+            path,
+            connections: parse_env("LOAD_CONNECTIONS", 32)?,
+            duration: Duration::from_secs(parse_env("LOAD_DURATION", 10)?),
+            token: env::var("LOAD_TOKEN").ok(),
+        })
+    }
+
+    fn request(&self) -> String {
+        let authorization = match &self.token {
+            Some(token) => format!("Authorization: Bearer {}\r\n", token),
+            None => String::new(),
+        };
+        format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\n{}\r\n",
+            self.path, self.address, authorization
+        )
+    }
+}
+
+fn parse_env<T: std::str::FromStr>(name: &str, default: T) -> Result<T, String> {
+    match env::var(name) {
+        Ok(value) => value.parse().map_err(|_| format!("invalid {}: {}", name, value)),
+        Err(_) => Ok(default),
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    latencies: Vec<Duration>,
+    statuses: BTreeMap<u16, u64>,
+    errors: u64,
+}
+
+impl Stats {
+    fn merge(&mut self, other: Stats) {
+        self.latencies.extend(other.latencies);
+        for (status, count) in other.statuses {
+            *self.statuses.entry(status).or_default() += count;
+        }
+        self.errors += other.errors;
+    }
+
+    fn percentile(&self, p: f64) -> Duration {
+        let index = ((self.latencies.len() - 1) as f64 * p).round() as usize;
+        self.latencies[index]
+    }
+}
+
+/// Reads one response and returns its status. Body length must be given by `Content-Length`.
+fn read_response(reader: &mut BufReader<TcpStream>) -> io::Result<u16> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid status line"))?;
+
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+
+    io::copy(&mut reader.by_ref().take(content_length), &mut io::sink())?;
+    Ok(status)
+}
+
+fn run_connection(settings: &Settings, request: &[u8], stop: &AtomicBool) -> Stats {
+    let mut stats = Stats::default();
+    while !stop.load(Ordering::Relaxed) {
+        let stream = match TcpStream::connect(&settings.address) {
+            Ok(stream) => stream,
+            Err(_) => {
+                stats.errors += 1;
+                thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+        };
+        let _ = stream.set_nodelay(true);
+        let mut writer = match stream.try_clone() {
+            Ok(writer) => writer,
+            Err(_) => {
+                stats.errors += 1;
+                continue;
+            }
+        };
+        let mut reader = BufReader::new(stream);
+
+        // Reuses the connection until it fails, then reconnects.
+        while !stop.load(Ordering::Relaxed) {
+            let started = Instant::now();
+            let status = writer
+                .write_all(request)
+                .and_then(|_| read_response(&mut reader));
+            match status {
+                Ok(status) => {
+                    stats.latencies.push(started.elapsed());
+                    *stats.statuses.entry(status).or_default() += 1;
+                }
+                Err(_) => {
+                    stats.errors += 1;
+                    break;
+                }
+            }
+        }
+    }
+    stats
+}
+
+fn main() {
+    let settings = match Settings::from_env() {
+        Ok(settings) => Arc::new(settings),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    println!(
+        "GET http://{}{} with {} connections for {:?}",
+        settings.address, settings.path, settings.connections, settings.duration
+    );
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let workers: Vec<_> = (0..settings.connections)
+        .map(|_| {
+            let settings = Arc::clone(&settings);
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || run_connection(&settings, settings.request().as_bytes(), &stop))
+        })
+        .collect();
+
+    let started = Instant::now();
+    thread::sleep(settings.duration);
+    stop.store(true, Ordering::Relaxed);
+
+    let mut stats = Stats::default();
+    for worker in workers {
+        stats.merge(worker.join().expect("load thread panicked"));
+    }
+    let elapsed = started.elapsed();
+
+    let total = stats.latencies.len();
+    println!(
+        "{} responses, {:.0} req/s, {} errors",
+        total,
+        total as f64 / elapsed.as_secs_f64(),
+        stats.errors
+    );
+    if total > 0 {
+        stats.latencies.sort_unstable();
+        println!(
+            "latency p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+            stats.percentile(0.5),
+            stats.percentile(0.9),
+            stats.percentile(0.99),
+            stats.latencies[total - 1]
+        );
+    }
+    for (status, count) in stats.statuses.iter() {
+        println!("  {}: {}", status, count);
+    }
+}
@@ -0,0 +1,209 @@
+use crate::config::AuthConfig;
+use crate::errors::{CustomError, CustomResult};
+use actix_web::dev::{Payload, ServiceRequest};
+use actix_web::http::{header, HeaderMap};
+use actix_web::{web, FromRequest, HttpRequest};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use futures::future::{ready, Ready};
+use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenKind {
+    Access,
+    Refresh,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Claims {
+    /// User id.
+    sub: String,
+    iat: i64,
+    exp: i64,
+    kind: TokenKind,
+}
+
+/// Token pair returned on login and refresh.
+#[derive(Serialize, Debug)]
+pub struct Tokens {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub token_type: &'static str,
+    /// Access token lifetime, seconds.
+    pub expires_in: u64,
+}
+
+/// Issues and verifies JWT tokens.
+pub struct Auth {
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    access_token_ttl: u64,
+    refresh_token_ttl: u64,
+}
+
+impl Auth {
+    pub fn new(config: &AuthConfig) -> Self {
+        let secret = config.jwt_secret.as_bytes();
+        Self {
+            encoding_key: EncodingKey::from_secret(secret),
+            decoding_key: DecodingKey::from_secret(secret),
+            access_token_ttl: config.access_token_ttl,
+            refresh_token_ttl: config.refresh_token_ttl,
+        }
+    }
+
+    pub fn issue_tokens(&self, user_id: &ObjectId) -> CustomResult<Tokens> {
+        Ok(Tokens {
+            access_token: self.issue(user_id, TokenKind::Access, self.access_token_ttl)?,
+            refresh_token: self.issue(user_id, TokenKind::Refresh, self.refresh_token_ttl)?,
+            token_type: "Bearer",
+            expires_in: self.access_token_ttl,
+        })
+    }
+
+    fn issue(&self, user_id: &ObjectId, kind: TokenKind, ttl: u64) -> CustomResult<String> {
+        let now = chrono::Utc::now().timestamp();
+        let claims = Claims {
+            sub: user_id.to_hex(),
+            iat: now,
+            exp: now + ttl as i64,
+            kind,
+        };
+        jsonwebtoken::encode(&Header::default(), &claims, &self.encoding_key)
+            .map_err(|e| CustomError::InternalError(format!("Can't issue token: {}", e)))
+    }
+
+    /// Checks token signature, expiration and kind. Returns id of the user it was issued to.
+    pub fn verify(&self, token: &str, kind: TokenKind) -> CustomResult<ObjectId> {
+        let data = jsonwebtoken::decode::<Claims>(token, &self.decoding_key, &Validation::default())?;
+        if data.claims.kind != kind {
+            return Err(CustomError::Unauthorized("wrong token kind".into()));
+        }
+        ObjectId::from_str(&data.claims.sub)
+            .map_err(|_| CustomError::Unauthorized("invalid token subject".into()))
+    }
+}
+
+/// Hashes password with Argon2 and random salt. CPU heavy, so runs on the blocking pool.
+pub async fn hash_password(password: String) -> CustomResult<String> {
+    web::block(move || {
+        let salt = SaltString::generate(&mut rand::rngs::OsRng);
+        let hash = Argon2::default().hash_password(password.as_bytes(), &salt);
+        hash.map(|hash| hash.to_string())
+            .map_err(|e| CustomError::InternalError(format!("Can't hash password: {}", e)))
+    })
+    .await
+    .map_err(|e| CustomError::InternalError(e.to_string()))?
+}
+
+pub async fn verify_password(password: String, password_hash: String) -> CustomResult<bool> {
+    web::block(move || {
+        let hash = PasswordHash::new(&password_hash)
+            .map_err(|e| CustomError::InternalError(format!("Invalid password hash: {}", e)))?;
+        let verified = Argon2::default().verify_password(password.as_bytes(), &hash);
+        Ok(verified.is_ok())
+    })
+    .await
+    .map_err(|e| CustomError::InternalError(e.to_string()))?
+}
+
+/// Authenticated user. Extracting it from a request without valid access token fails with 401.
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub id: ObjectId,
+}
+
+impl AuthUser {
+    fn authenticate(req: &HttpRequest, token: &str) -> CustomResult<Self> {
+        let auth = req
+            .app_data::<web::Data<Arc<Auth>>>()
+            .ok_or_else(|| CustomError::InternalError("Authentication isn't configured".into()))?;
+
+        let id = auth.verify(token, TokenKind::Access)?;
+        Ok(Self { id })
+    }
+}
+
+/// Token from `Authorization: Bearer <token>` header.
+pub fn bearer_token(headers: &HeaderMap) -> CustomResult<&str> {
+    let header = headers
+        .get(header::AUTHORIZATION)
+        .ok_or_else(|| CustomError::Unauthorized("missing Authorization header".into()))?;
+    let token = header
+        .to_str()
+        .ok()
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .ok_or_else(|| CustomError::Unauthorized("expected Bearer token".into()))?;
+    Ok(token.trim())
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    access_token: Option<String>,
+}
+
+/// Token of a board event stream request: from `Authorization` header or, as browsers can't set
+/// headers of `EventSource` requests, from `access_token` query parameter.
+pub fn stream_token(headers: &HeaderMap, query_string: &str) -> CustomResult<String> {
+    if headers.contains_key(header::AUTHORIZATION) {
+        return bearer_token(headers).map(str::to_string);
+    }
+    let query = web::Query::<TokenQuery>::from_query(query_string)
+        .map_err(|e| CustomError::BadRequest(e.to_string()))?;
+    query.into_inner().access_token.ok_or_else(|| {
+        CustomError::Unauthorized("missing Authorization header or access_token parameter".into())
+    })
+}
+
+/// Hides the `access_token` query parameter in a request line of the access log.
+pub fn redacted_request_line(req: &ServiceRequest) -> String {
+    let query: Vec<String> = req
+        .query_string()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some(("access_token", _)) => "access_token=***".to_string(),
+            _ => pair.to_string(),
+        })
+        .collect();
+    if query.is_empty() {
+        format!("{} {} {:?}", req.method(), req.path(), req.version())
+    } else {
+        let query = query.join("&");
+        format!("{} {}?{} {:?}", req.method(), req.path(), query, req.version())
+    }
+}
+
+impl FromRequest for AuthUser {
+    type Config = ();
+    type Error = CustomError;
+    type Future = Ready<CustomResult<Self>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let token = bearer_token(req.headers());
+        ready(token.and_then(|token| Self::authenticate(req, token)))
+    }
+}
+
+/// Authenticated user of a board event stream, see [`stream_token`].
+#[derive(Debug, Clone)]
+pub struct StreamUser {
+    pub id: ObjectId,
+}
+
+impl FromRequest for StreamUser {
+    type Config = ();
+    type Error = CustomError;
+    type Future = Ready<CustomResult<Self>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let token = stream_token(req.headers(), req.query_string());
+        let user = token.and_then(|token| AuthUser::authenticate(req, &token));
+        ready(user.map(|user| Self { id: user.id }))
+    }
+}
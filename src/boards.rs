@@ -1,6 +1,10 @@
 use crate::db::{BoardsDatabase, EventMsgReceiver};
-use crate::errors::CustomResult;
-use crate::models::Board;
+use crate::errors::{CustomError, CustomResult};
+use crate::models::{
+    Board, BoardFilter, BoardPatch, ListQuery, Member, Page, Patch, Precondition, Role, SortField,
+};
+use crate::validation::Validate;
+use mongodb::bson::oid::ObjectId;
 
 pub struct Boards {
     db: Box<dyn BoardsDatabase>,
@@ -11,30 +15,95 @@ impl Boards {
         Self { db }
     }
 
-    pub async fn read_boards(&self) -> CustomResult<Vec<Board>> {
-        self.db.read_boards().await
+    /// Reads board checking that user has at least `required` role on it.
+    async fn authorize(&self, id: &str, user_id: &ObjectId, required: Role) -> CustomResult<Board> {
+        let board = self.db.read_board(id).await?;
+        board.authorize(user_id, required)?;
+        Ok(board)
     }
 
-    pub async fn create_board(&self, board: Board) -> CustomResult<Board> {
+    /// Lists boards the user is a member of.
+    pub async fn read_boards(&self, query: &ListQuery, user_id: &ObjectId) -> CustomResult<Page<Board>> {
+        if query.sort() == SortField::Rank {
+            return Err(CustomError::BadRequest("boards have no manual order".into()));
+        }
+        let filter = BoardFilter {
+            member: Some(*user_id),
+        };
+        self.db.read_boards(query, &filter).await
+    }
+
+    pub async fn create_board(&self, mut board: Board, user_id: &ObjectId) -> CustomResult<Board> {
+        board.validate()?;
+        board.created_by = Some(*user_id);
+        board.members = vec![Member {
+            user_id: *user_id,
+            role: Role::Owner,
+        }];
         self.db.create_board(board).await
     }
 
-    pub async fn read_board(&self, id: &str) -> CustomResult<Board> {
-        self.db.read_board(id).await
+    pub async fn read_board(&self, id: &str, user_id: &ObjectId) -> CustomResult<Board> {
+        self.authorize(id, user_id, Role::Viewer).await
     }
 
-    pub async fn update_board(&self, id: &str, board: Board) -> CustomResult<Board> {
+    pub async fn update_board(
+        &self,
+        id: &str,
+        mut board: Board,
+        if_match: &Precondition,
+        user_id: &ObjectId,
+    ) -> CustomResult<Board> {
+        // Stages and members are changed through their own endpoints only.
+        let stored = self.authorize(id, user_id, Role::Owner).await?;
+        if_match.check(stored.version)?;
+        board.version = stored.version;
+        board.stages = stored.stages;
+        board.created_by = stored.created_by;
+        board.members = stored.members;
+        board.validate()?;
         self.db.update_board(id, board).await
     }
 
-    pub async fn delete_board(&self, id: &str) -> CustomResult<Board> {
-        self.db.delete_board(id).await
+    /// Changes only fields the patch touches. Stages and members can't be patched.
+    pub async fn patch_board(
+        &self,
+        id: &str,
+        patch: &Patch,
+        if_match: &Precondition,
+        user_id: &ObjectId,
+    ) -> CustomResult<Board> {
+        let stored = self.authorize(id, user_id, Role::Owner).await?;
+        if_match.check(stored.version)?;
+        let (patched, changed) = patch.apply(&stored, BoardPatch::EDITABLE)?;
+        patched.validate()?;
+        let board_patch = BoardPatch::new(patched, &changed);
+        if board_patch.is_empty() {
+            return Ok(stored);
+        }
+        self.db.patch_board(id, stored.version, &board_patch).await
+    }
+
+    pub async fn delete_board(
+        &self,
+        id: &str,
+        if_match: &Precondition,
+        user_id: &ObjectId,
+    ) -> CustomResult<Board> {
+        let stored = self.authorize(id, user_id, Role::Owner).await?;
+        if_match.check(stored.version)?;
+        self.db.delete_board(id, stored.version).await
     }
 
     pub async fn subscribe_on_board_updates(
         &self,
         board_id: &str,
+        last_event_id: Option<u64>,
+        user_id: &ObjectId,
     ) -> CustomResult<EventMsgReceiver> {
-        self.db.subscribe_on_board_updates(board_id).await
+        self.authorize(board_id, user_id, Role::Viewer).await?;
+        self.db
+            .subscribe_on_board_updates(board_id, last_event_id)
+            .await
     }
 }
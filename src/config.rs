@@ -0,0 +1,474 @@
+use clap::Parser;
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Can't read config file {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Can't parse config file {0}: {1}")]
+    Parse(PathBuf, toml::de::Error),
+    #[error("Invalid configuration: {}", .0.join("; "))]
+    Invalid(Vec<String>),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Storage {
+    Mongo,
+    Sql,
+    Memory,
+}
+
+impl Storage {
+    /// Label of the storage in metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Mongo => "mongo",
+            Self::Sql => "sql",
+            Self::Memory => "memory",
+        }
+    }
+}
+
+/// How requests are counted against the limit.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum RateLimitAlgorithm {
+    /// Counter reset every window. Up to twice the limit passes around a window boundary.
+    FixedWindow,
+    /// Timestamps of requests made during the last window.
+    SlidingLog,
+    /// Token bucket as GCRA: requests are spread evenly, bursts up to the limit are allowed.
+    TokenBucket,
+}
+
+/// What the rate limiter does while Redis is unreachable.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitFallback {
+    /// Let every request through.
+    Open,
+    /// Reject every request with 503.
+    Closed,
+    /// Count requests in token buckets of every worker, each one allowing the full quota.
+    Local,
+}
+
+impl RateLimitFallback {
+    /// Label of the fallback in metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Open => "open",
+            Self::Closed => "closed",
+            Self::Local => "local",
+        }
+    }
+}
+
+/// Requests allowed per client during a window.
+#[derive(Debug, Copy, Clone)]
+pub struct Quota {
+    pub max_requests: u64,
+    /// Window length, seconds.
+    pub window: u64,
+}
+
+#[derive(Clone)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    pub algorithm: RateLimitAlgorithm,
+    pub fallback: RateLimitFallback,
+    /// Quota of requests reading data.
+    pub read: Quota,
+    /// Quota of requests changing data.
+    pub write: Quota,
+    /// Event streams a client may keep open at once.
+    pub max_streams: u64,
+    /// Keys sent in `X-Api-Key`. Requests with them are limited per key.
+    pub api_keys: Vec<String>,
+    /// Proxies whose `X-Forwarded-For` is trusted to find client address.
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Clients which are never limited, e.g. internal services.
+    pub allowlist: Vec<IpAddr>,
+}
+
+impl std::fmt::Debug for RateLimitConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RateLimitConfig")
+            .field("enabled", &self.enabled)
+            .field("algorithm", &self.algorithm)
+            .field("fallback", &self.fallback)
+            .field("read", &self.read)
+            .field("write", &self.write)
+            .field("max_streams", &self.max_streams)
+            .field("api_keys", &format!("{} keys", self.api_keys.len()))
+            .field("trusted_proxies", &self.trusted_proxies)
+            .field("allowlist", &self.allowlist)
+            .finish()
+    }
+}
+
+#[derive(Clone)]
+pub struct AuthConfig {
+    /// Secret signing JWT tokens.
+    pub jwt_secret: String,
+    /// Lifetime of access tokens, seconds.
+    pub access_token_ttl: u64,
+    /// Lifetime of refresh tokens, seconds.
+    pub refresh_token_ttl: u64,
+}
+
+impl std::fmt::Debug for AuthConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthConfig")
+            .field("jwt_secret", &"***")
+            .field("access_token_ttl", &self.access_token_ttl)
+            .field("refresh_token_ttl", &self.refresh_token_ttl)
+            .finish()
+    }
+}
+
+/// Validated application configuration.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub storage: Storage,
+    pub mongo_connection: Option<String>,
+    pub sql_connection: Option<String>,
+    pub redis_connection: Option<String>,
+    /// Wrap storage with Redis cache and pub/sub.
+    pub cache: bool,
+    pub host: String,
+    pub port: u16,
+    pub workers: Option<usize>,
+    pub rate_limit: RateLimitConfig,
+    pub auth: AuthConfig,
+    /// Number of the latest events kept per board for SSE resume.
+    pub event_log_size: usize,
+    /// Retry connecting to MongoDB, SQL database and Redis on start instead of failing.
+    pub wait_for_dependencies: bool,
+    /// How long to retry connecting on start, seconds.
+    pub startup_timeout: u64,
+}
+
+/// One source of settings. Every field is optional, so sources can be layered:
+/// command line flags override environment variables, which override the config file.
+#[derive(Debug, Default, Parser, Deserialize)]
+#[clap(version, about = "Task boards application backend")]
+#[serde(default, deny_unknown_fields)]
+struct ConfigLayer {
+    /// Path to TOML config file
+    #[clap(long, env = "CONFIG_FILE", value_parser)]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+    /// Storage backend
+    #[clap(long, env = "STORAGE", value_enum)]
+    storage: Option<Storage>,
+    #[clap(long, env = "MONGO_CONNECTION", value_parser)]
+    mongo_connection: Option<String>,
+    #[clap(long, env = "SQL_CONNECTION", value_parser)]
+    sql_connection: Option<String>,
+    #[clap(long, env = "REDIS_CONNECTION", value_parser)]
+    redis_connection: Option<String>,
+    /// Enable Redis cache layer [default: enabled if Redis connection is set]
+    #[clap(long, env = "CACHE", value_parser)]
+    cache: Option<bool>,
+    /// Address to bind [default: 127.0.0.1]
+    #[clap(long, env = "HOST", value_parser)]
+    host: Option<String>,
+    /// Port to bind [default: 9000]
+    #[clap(long, env = "PORT", value_parser)]
+    port: Option<u16>,
+    /// Number of HTTP workers [default: number of CPUs]
+    #[clap(long, env = "WORKERS", value_parser)]
+    workers: Option<usize>,
+    /// Enable rate limiter [default: enabled if Redis connection is set]
+    #[clap(long, env = "RATE_LIMIT", value_parser)]
+    rate_limit: Option<bool>,
+    /// Rate limiting algorithm [default: token-bucket]
+    #[clap(long, env = "RATE_LIMIT_ALGORITHM", value_enum)]
+    rate_limit_algorithm: Option<RateLimitAlgorithm>,
+    /// Rate limiting while Redis is unreachable [default: local]
+    #[clap(long, env = "RATE_LIMIT_FALLBACK", value_enum)]
+    rate_limit_fallback: Option<RateLimitFallback>,
+    /// Reading requests allowed per client during the window [default: 60]
+    #[clap(long, env = "RATE_LIMIT_MAX_REQUESTS", value_parser)]
+    rate_limit_max_requests: Option<u64>,
+    /// Rate limiting window of reading requests in seconds [default: 60]
+    #[clap(long, env = "RATE_LIMIT_WINDOW", value_parser)]
+    rate_limit_window: Option<u64>,
+    /// Changing requests allowed per client during the window [default: 20]
+    #[clap(long, env = "RATE_LIMIT_WRITE_MAX_REQUESTS", value_parser)]
+    rate_limit_write_max_requests: Option<u64>,
+    /// Rate limiting window of changing requests in seconds [default: rate_limit_window]
+    #[clap(long, env = "RATE_LIMIT_WRITE_WINDOW", value_parser)]
+    rate_limit_write_window: Option<u64>,
+    /// Board event streams (SSE and WebSocket) a client may keep open at once [default: 5]
+    #[clap(long, env = "RATE_LIMIT_MAX_STREAMS", value_parser)]
+    rate_limit_max_streams: Option<u64>,
+    /// Comma separated API keys, limited per key instead of per user or address
+    #[clap(
+        long,
+        env = "RATE_LIMIT_API_KEYS",
+        value_parser,
+        value_delimiter = ',',
+        hide_env_values = true
+    )]
+    rate_limit_api_keys: Option<Vec<String>>,
+    /// Comma separated proxy addresses allowed to set X-Forwarded-For
+    #[clap(long, env = "RATE_LIMIT_TRUSTED_PROXIES", value_parser, value_delimiter = ',')]
+    rate_limit_trusted_proxies: Option<Vec<IpAddr>>,
+    /// Comma separated client addresses which are never limited
+    #[clap(long, env = "RATE_LIMIT_ALLOWLIST", value_parser, value_delimiter = ',')]
+    rate_limit_allowlist: Option<Vec<IpAddr>>,
+    /// Secret signing JWT tokens, at least 32 bytes
+    #[clap(long, env = "JWT_SECRET", value_parser, hide_env_values = true)]
+    jwt_secret: Option<String>,
+    /// Lifetime of access tokens in seconds [default: 900]
+    #[clap(long, env = "ACCESS_TOKEN_TTL", value_parser)]
+    access_token_ttl: Option<u64>,
+    /// Lifetime of refresh tokens in seconds [default: 2592000]
+    #[clap(long, env = "REFRESH_TOKEN_TTL", value_parser)]
+    refresh_token_ttl: Option<u64>,
+    /// Latest events kept per board to replay to reconnecting subscribers [default: 1000]
+    #[clap(long, env = "EVENT_LOG_SIZE", value_parser)]
+    event_log_size: Option<usize>,
+    /// Retry connecting to dependencies on start with backoff instead of failing [default: false]
+    #[clap(long, env = "WAIT_FOR_DEPENDENCIES", value_parser)]
+    wait_for_dependencies: Option<bool>,
+    /// How long to wait for dependencies on start in seconds [default: 60]
+    #[clap(long, env = "STARTUP_TIMEOUT", value_parser)]
+    startup_timeout: Option<u64>,
+}
+
+impl ConfigLayer {
+    fn from_file(path: PathBuf) -> Result<Self, ConfigError> {
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) => return Err(ConfigError::Read(path, e)),
+        };
+        toml::from_str(&content).map_err(|e| ConfigError::Parse(path, e))
+    }
+
+    /// Takes values missing in `self` from `other`.
+    fn or(self, other: Self) -> Self {
+        Self {
+            config: self.config.or(other.config),
+            storage: self.storage.or(other.storage),
+            mongo_connection: self.mongo_connection.or(other.mongo_connection),
+            sql_connection: self.sql_connection.or(other.sql_connection),
+            redis_connection: self.redis_connection.or(other.redis_connection),
+            cache: self.cache.or(other.cache),
+            host: self.host.or(other.host),
+            port: self.port.or(other.port),
+            workers: self.workers.or(other.workers),
+            rate_limit: self.rate_limit.or(other.rate_limit),
+            rate_limit_algorithm: self.rate_limit_algorithm.or(other.rate_limit_algorithm),
+            rate_limit_fallback: self.rate_limit_fallback.or(other.rate_limit_fallback),
+            rate_limit_max_requests: self.rate_limit_max_requests.or(other.rate_limit_max_requests),
+            rate_limit_window: self.rate_limit_window.or(other.rate_limit_window),
+            rate_limit_write_max_requests: self
+                .rate_limit_write_max_requests
+                .or(other.rate_limit_write_max_requests),
+            rate_limit_write_window: self.rate_limit_write_window.or(other.rate_limit_write_window),
+            rate_limit_max_streams: self.rate_limit_max_streams.or(other.rate_limit_max_streams),
+            rate_limit_api_keys: self.rate_limit_api_keys.or(other.rate_limit_api_keys),
+            rate_limit_trusted_proxies: self
+                .rate_limit_trusted_proxies
+                .or(other.rate_limit_trusted_proxies),
+            rate_limit_allowlist: self.rate_limit_allowlist.or(other.rate_limit_allowlist),
+            jwt_secret: self.jwt_secret.or(other.jwt_secret),
+            access_token_ttl: self.access_token_ttl.or(other.access_token_ttl),
+            refresh_token_ttl: self.refresh_token_ttl.or(other.refresh_token_ttl),
+            event_log_size: self.event_log_size.or(other.event_log_size),
+            wait_for_dependencies: self.wait_for_dependencies.or(other.wait_for_dependencies),
+            startup_timeout: self.startup_timeout.or(other.startup_timeout),
+        }
+    }
+}
+
+impl Config {
+    /// Loads config from command line, environment and config file.
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut layer = ConfigLayer::parse();
+        if let Some(path) = layer.config.take() {
+            layer = layer.or(ConfigLayer::from_file(path)?);
+        }
+        Self::from_layer(layer)
+    }
+
+    fn from_layer(layer: ConfigLayer) -> Result<Self, ConfigError> {
+        let redis_configured = layer.redis_connection.is_some();
+        let rate_limit_window = layer.rate_limit_window.unwrap_or(60);
+        let config = Self {
+            storage: layer.storage.unwrap_or(Storage::Mongo),
+            mongo_connection: layer.mongo_connection,
+            sql_connection: layer.sql_connection,
+            redis_connection: layer.redis_connection,
+            cache: layer.cache.unwrap_or(redis_configured),
+            host: layer.host.unwrap_or_else(|| "127.0.0.1".into()),
+            port: layer.port.unwrap_or(9000),
+            workers: layer.workers,
+            rate_limit: RateLimitConfig {
+                enabled: layer.rate_limit.unwrap_or(redis_configured),
+                algorithm: layer
+                    .rate_limit_algorithm
+                    .unwrap_or(RateLimitAlgorithm::TokenBucket),
+                fallback: layer.rate_limit_fallback.unwrap_or(RateLimitFallback::Local),
+                read: Quota {
+                    max_requests: layer.rate_limit_max_requests.unwrap_or(60),
+                    window: rate_limit_window,
+                },
+                write: Quota {
+                    max_requests: layer.rate_limit_write_max_requests.unwrap_or(20),
+                    window: layer.rate_limit_write_window.unwrap_or(rate_limit_window),
+                },
+                max_streams: layer.rate_limit_max_streams.unwrap_or(5),
+                api_keys: layer.rate_limit_api_keys.unwrap_or_default(),
+                trusted_proxies: layer.rate_limit_trusted_proxies.unwrap_or_default(),
+                allowlist: layer.rate_limit_allowlist.unwrap_or_default(),
+            },
+            auth: AuthConfig {
+                jwt_secret: layer.jwt_secret.unwrap_or_default(),
+                access_token_ttl: layer.access_token_ttl.unwrap_or(15 * 60),
+                refresh_token_ttl: layer.refresh_token_ttl.unwrap_or(30 * 24 * 60 * 60),
+            },
+            event_log_size: layer.event_log_size.unwrap_or(1000),
+            wait_for_dependencies: layer.wait_for_dependencies.unwrap_or(false),
+            startup_timeout: layer.startup_timeout.unwrap_or(60),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+
+        match self.storage {
+            Storage::Mongo if self.mongo_connection.is_none() => {
+                errors.push("mongo storage requires mongo_connection".to_string())
+            }
+            Storage::Sql if self.sql_connection.is_none() => {
+                errors.push("sql storage requires sql_connection".to_string())
+            }
+            _ => {}
+        }
+
+        if self.redis_connection.is_none() {
+            if self.cache {
+                errors.push("cache requires redis_connection".into());
+            }
+            if self.rate_limit.enabled {
+                errors.push("rate_limit requires redis_connection".into());
+            }
+        }
+
+        if self.workers == Some(0) {
+            errors.push("workers must be positive".into());
+        }
+
+        let quotas = [&self.rate_limit.read, &self.rate_limit.write];
+        if quotas.iter().any(|quota| quota.max_requests == 0) {
+            errors.push("rate limit max requests must be positive".into());
+        }
+
+        if quotas.iter().any(|quota| quota.window == 0) {
+            errors.push("rate limit windows must be positive".into());
+        }
+
+        if self.rate_limit.max_streams == 0 {
+            errors.push("rate_limit_max_streams must be positive".into());
+        }
+
+        if self.rate_limit.api_keys.iter().any(|key| key.is_empty()) {
+            errors.push("rate_limit_api_keys must not be empty".into());
+        }
+
+        if self.event_log_size == 0 {
+            errors.push("event_log_size must be positive".into());
+        }
+
+        if self.wait_for_dependencies && self.startup_timeout == 0 {
+            errors.push("startup_timeout must be positive".into());
+        }
+
+        if self.auth.jwt_secret.len() < 32 {
+            errors.push("jwt_secret must be at least 32 bytes long".into());
+        }
+
+        if self.auth.access_token_ttl == 0 || self.auth.refresh_token_ttl == 0 {
+            errors.push("token lifetimes must be positive".into());
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(errors))
+        }
+    }
+
+    pub fn bind_address(&self) -> (&str, u16) {
+        (&self.host, self.port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "secret of the tests, 32 bytes long";
+
+    fn file(content: &str) -> ConfigLayer {
+        toml::from_str(content).unwrap()
+    }
+
+    #[test]
+    fn flags_override_environment_which_overrides_file() {
+        // No other test reads these variables.
+        std::env::set_var("EVENT_LOG_SIZE", "50");
+        std::env::set_var("STARTUP_TIMEOUT", "30");
+        let flags = ["boards_back", "--startup-timeout", "10", "--storage", "memory"];
+        let layer = ConfigLayer::try_parse_from(flags).unwrap();
+        std::env::remove_var("EVENT_LOG_SIZE");
+        std::env::remove_var("STARTUP_TIMEOUT");
+
+        let from_file = file(&format!(
+            "storage = \"sql\"\nevent_log_size = 200\nstartup_timeout = 5\nport = 9100\n\
+            jwt_secret = \"{}\"",
+            SECRET
+        ));
+        let config = Config::from_layer(layer.or(from_file)).unwrap();
+        assert_eq!(config.storage, Storage::Memory);
+        assert_eq!(config.startup_timeout, 10);
+        assert_eq!(config.event_log_size, 50);
+        assert_eq!(config.port, 9100);
+        assert_eq!(config.auth.jwt_secret, SECRET);
+        // Defaults fill the rest.
+        assert_eq!(config.host, "127.0.0.1");
+        assert!(!config.cache && !config.rate_limit.enabled);
+    }
+
+    #[test]
+    fn reports_every_invalid_setting() {
+        let layer = file("storage = \"sql\"\njwt_secret = \"too short\"\nworkers = 0");
+        let errors = match Config::from_layer(layer) {
+            Err(ConfigError::Invalid(errors)) => errors,
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        };
+        assert_eq!(
+            errors,
+            [
+                "sql storage requires sql_connection",
+                "workers must be positive",
+                "jwt_secret must be at least 32 bytes long",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_file_settings() {
+        assert!(toml::from_str::<ConfigLayer>("prot = 9000").is_err());
+    }
+}
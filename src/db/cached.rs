@@ -1,70 +1,152 @@
-use crate::db::{BoardsDatabase, EventMsgReceiver, EventMsgResult, TasksDatabase};
+use crate::db::{BoardsDatabase, EventMsgReceiver, EventMsgResult, TasksDatabase, UsersDatabase};
 use crate::errors::CustomResult;
-use crate::models::{Board, Task};
-use actix_web::web::Bytes;
-use redis::{AsyncCommands, Client, Commands, FromRedisValue};
+use crate::events::{self, BoardEvent, StoredEvent};
+use crate::metrics;
+use crate::models::{
+    Board, BoardFilter, BoardPatch, ListQuery, Page, Task, TaskFilter, TaskPatch, User,
+};
+use crate::request_id;
+use mongodb::bson::oid::ObjectId;
+use redis::{AsyncCommands, Client, FromRedisValue, Script};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use std::future::Future;
+use std::str::FromStr;
 use tokio::sync::mpsc;
 use tokio_stream::StreamExt;
+use tracing::Instrument;
+
+/// Assigns the next id to event, appends it to the bounded board log and publishes it.
+/// KEYS: id sequence, log. ARGV: event JSON, log size, channel, request id JSON.
+const PUBLISH_EVENT_SCRIPT: &str = r#"
+local id = redis.call('INCR', KEYS[1])
+local payload = '{"id":' .. id .. ',"request_id":' .. ARGV[4] .. ',"event":' .. ARGV[1] .. '}'
+redis.call('RPUSH', KEYS[2], payload)
+redis.call('LTRIM', KEYS[2], -tonumber(ARGV[2]), -1)
+redis.call('PUBLISH', ARGV[3], payload)
+return id
+"#;
+
+/// Measures and traces a Redis operation.
+async fn redis_call<F: Future>(operation: &'static str, future: F) -> F::Output {
+    let span = tracing::info_span!("redis", otel.name = operation, db.system = "redis");
+    metrics::timed("redis", operation, future)
+        .instrument(span)
+        .await
+}
 
 #[derive(Clone)]
 pub struct Cached<T: Clone> {
     db: T,
     redis_client: Client,
+    /// Number of the latest events kept per board for replay.
+    event_log_size: usize,
 }
 
 impl<T: Clone> Cached<T> {
-    pub fn new(db: T, redis_client: Client) -> Self {
-        Self { db, redis_client }
+    pub fn new(db: T, redis_client: Client, event_log_size: usize) -> Self {
+        Self {
+            db,
+            redis_client,
+            event_log_size,
+        }
     }
 
     async fn cache_set<V: Serialize>(&self, key: &str, field: &str, value: &V) -> CustomResult<()> {
-        let mut connection = self.redis_client.get_async_connection().await?;
-        let serialized = serde_json::ser::to_string(value)?;
-        redis::pipe()
-            .hset(key, field, &serialized)
-            .expire(&key, 60)
-            .query_async(&mut connection)
-            .await?;
-        Ok(())
+        redis_call("cache_set", async {
+            let mut connection = self.redis_client.get_async_connection().await?;
+            let serialized = serde_json::ser::to_string(value)?;
+            redis::pipe()
+                .hset(key, field, &serialized)
+                .expire(key, 60)
+                .query_async::<_, ()>(&mut connection)
+                .await?;
+            Ok(())
+        })
+        .await
     }
 
     async fn cache_get<V: DeserializeOwned>(&self, key: &str, field: &str) -> CustomResult<V> {
-        let mut connection = self.redis_client.get_async_connection().await?;
-        let serialized = connection.hget::<_, _, String>(&key, field).await?;
-        let deserialized = serde_json::de::from_str(&serialized)?;
-        Ok(deserialized)
+        redis_call("cache_get", async {
+            let mut connection = self.redis_client.get_async_connection().await?;
+            let serialized = connection.hget::<_, _, String>(&key, field).await?;
+            let deserialized = serde_json::de::from_str(&serialized)?;
+            Ok(deserialized)
+        })
+        .await
     }
 
     async fn cache_delete_field(&self, key: &str, field: &str) -> CustomResult<()> {
-        let mut connection = self.redis_client.get_async_connection().await?;
-        connection.hdel(&key, field).await?;
-        Ok(())
+        redis_call("cache_delete", async {
+            let mut connection = self.redis_client.get_async_connection().await?;
+            connection.hdel::<_, _, ()>(&key, field).await?;
+            Ok(())
+        })
+        .await
     }
 
     async fn cache_delete_key(&self, key: &str) -> CustomResult<()> {
-        let mut connection = self.redis_client.get_async_connection().await?;
-        connection.del(&key).await?;
-        Ok(())
+        redis_call("cache_delete", async {
+            let mut connection = self.redis_client.get_async_connection().await?;
+            connection.del::<_, ()>(&key).await?;
+            Ok(())
+        })
+        .await
     }
 
     fn pub_sub_channel_name(board_id: &str) -> String {
         format!("BOARD_EVENT_{}", board_id)
     }
 
-    fn pub_board_updated(&self, board_id: &str) -> CustomResult<()> {
-        let channel = Self::pub_sub_channel_name(board_id);
-        let mut client = self.redis_client.clone();
-        client.publish(&channel, "Board updated")?;
-        Ok(())
+    fn event_log_keys(board_id: &str) -> (String, String) {
+        (
+            format!("BOARD_EVENTS_SEQ_{}", board_id),
+            format!("BOARD_EVENTS_{}", board_id),
+        )
     }
 
-    fn pub_board_deleted(&self, board_id: &str) -> CustomResult<()> {
-        let channel = Self::pub_sub_channel_name(board_id);
-        let mut client = self.redis_client.clone();
-        client.publish(&channel, "Board deleted")?;
-        Ok(())
+    async fn publish(&self, event: &BoardEvent) -> CustomResult<()> {
+        redis_call("publish", async {
+            let board_id = event.board_id().to_hex();
+            let (seq_key, log_key) = Self::event_log_keys(&board_id);
+            let mut connection = self.redis_client.get_async_connection().await?;
+            let id = Script::new(PUBLISH_EVENT_SCRIPT)
+                .key(seq_key)
+                .key(log_key)
+                .arg(serde_json::to_string(event)?)
+                .arg(self.event_log_size)
+                .arg(Self::pub_sub_channel_name(&board_id))
+                .arg(serde_json::to_string(&request_id::current())?)
+                .invoke_async::<_, u64>(&mut connection)
+                .await?;
+            log::debug!(event = event.name(), event_id = id, board_id = board_id.as_str(); "Published event");
+            Ok(())
+        })
+        .await
+    }
+
+    async fn read_event_log(&self, board_id: &str) -> CustomResult<Vec<StoredEvent>> {
+        redis_call("read_event_log", async {
+            let (_, log_key) = Self::event_log_keys(board_id);
+            let mut connection = self.redis_client.get_async_connection().await?;
+            let payloads = connection.lrange::<_, Vec<String>>(&log_key, 0, -1).await?;
+            let mut log = Vec::with_capacity(payloads.len());
+            for payload in payloads.iter() {
+                log.push(serde_json::from_str(payload)?);
+            }
+            Ok(log)
+        })
+        .await
+    }
+
+    async fn delete_event_log(&self, board_id: &str) -> CustomResult<()> {
+        redis_call("delete_event_log", async {
+            let (seq_key, log_key) = Self::event_log_keys(board_id);
+            let mut connection = self.redis_client.get_async_connection().await?;
+            connection.del::<_, ()>(&[seq_key, log_key]).await?;
+            Ok(())
+        })
+        .await
     }
 }
 
@@ -74,17 +156,23 @@ impl<T: BoardsDatabase + Clone> BoardsDatabase for Cached<T> {
         self.db.create_board(data).await
     }
 
-    async fn read_boards(&self) -> CustomResult<Vec<Board>> {
-        self.db.read_boards().await
+    async fn read_boards(
+        &self,
+        query: &ListQuery,
+        filter: &BoardFilter,
+    ) -> CustomResult<Page<Board>> {
+        self.db.read_boards(query, filter).await
     }
 
     async fn read_board(&self, id: &str) -> CustomResult<Board> {
         Ok(match self.cache_get(id, "board").await {
             Ok(b) => {
+                metrics::cache_read("board", true);
                 log::trace!("Read board #{} from cache", id);
                 b
             }
             _ => {
+                metrics::cache_read("board", false);
                 log::trace!("Read board #{} from database", id);
                 let board = self.db.read_board(id).await?;
                 self.cache_set(id, "board", &board).await?;
@@ -94,43 +182,98 @@ impl<T: BoardsDatabase + Clone> BoardsDatabase for Cached<T> {
     }
 
     async fn update_board(&self, id: &str, data: Board) -> CustomResult<Board> {
+        let old = self.read_board(id).await?;
         let updated = self.db.update_board(id, data).await?;
         self.cache_set(id, "board", &updated).await?;
-        self.pub_board_updated(id)?;
+        self.publish(&BoardEvent::board_updated(
+            ObjectId::from_str(id)?,
+            &old,
+            updated.clone(),
+        )?)
+        .await?;
         Ok(updated)
     }
 
-    async fn delete_board(&self, id: &str) -> CustomResult<Board> {
+    async fn patch_board(&self, id: &str, version: u64, patch: &BoardPatch) -> CustomResult<Board> {
+        let old = self.read_board(id).await?;
+        let patched = self.db.patch_board(id, version, patch).await?;
+        self.cache_set(id, "board", &patched).await?;
+        self.publish(&BoardEvent::board_updated(
+            ObjectId::from_str(id)?,
+            &old,
+            patched.clone(),
+        )?)
+        .await?;
+        Ok(patched)
+    }
+
+    async fn delete_board(&self, id: &str, version: u64) -> CustomResult<Board> {
         self.cache_delete_key(id).await?;
-        let board = self.db.delete_board(id).await?;
-        self.pub_board_deleted(id)?;
+        let board = self.db.delete_board(id, version).await?;
+        self.publish(&BoardEvent::BoardDeleted {
+            board_id: ObjectId::from_str(id)?,
+        })
+        .await?;
+        self.delete_event_log(id).await?;
         Ok(board)
     }
 
-    async fn subscribe_on_board_updates(&self, board_id: &str) -> CustomResult<EventMsgReceiver> {
-        let _ = self.read_board(board_id).await?;
+    async fn subscribe_on_board_updates(
+        &self,
+        board_id: &str,
+        last_event_id: Option<u64>,
+    ) -> CustomResult<EventMsgReceiver> {
+        let board = self.read_board(board_id).await?;
         let (tx, rx) = mpsc::channel::<EventMsgResult>(100);
-        tx.send(Ok("Connected;\n".into()))
-            .await
-            .expect("Can't send message to board events stream");
-
-        let mut pub_sub = self
-            .redis_client
-            .get_async_connection()
-            .await?
-            .into_pubsub();
-
-        pub_sub
-            .subscribe(&Self::pub_sub_channel_name(board_id))
-            .await?;
+        let mut pub_sub = redis_call("subscribe", async {
+            let mut pub_sub = self
+                .redis_client
+                .get_async_connection()
+                .await?
+                .into_pubsub();
+            pub_sub
+                .subscribe(&Self::pub_sub_channel_name(board_id))
+                .await?;
+            CustomResult::Ok(pub_sub)
+        })
+        .await?;
+
+        // Log is read after subscribing, so events published meanwhile come twice: skip them.
+        let (missed, replayed_up_to) = match last_event_id {
+            Some(last_id) => {
+                let log = self.read_event_log(board_id).await?;
+                let latest_id = log.last().map_or(0, |event| event.id);
+                let board_id = board.id.unwrap_or_default();
+                (events::replay(log, board_id, last_id), latest_id)
+            }
+            None => (Vec::new(), 0),
+        };
 
         tokio::spawn(async move {
+            for event in missed {
+                if tx.send(Ok(event)).await.is_err() {
+                    return;
+                }
+            }
+
             while let Some(event) = pub_sub.on_message().next().await {
                 let payload = event.get_payload().expect("Can't get message payload");
                 let payload: String = FromRedisValue::from_redis_value(&payload)
                     .expect("Can't convert event message from redis value");
-                let msg = Bytes::from(format!("Board event: {};\n", payload));
-                tx.send(Ok(msg)).await.expect("Events stream destroyed");
+                let event = match serde_json::from_str::<StoredEvent>(&payload) {
+                    Ok(event) => event,
+                    Err(e) => {
+                        log::warn!("Skipping malformed board event: {}", e);
+                        continue;
+                    }
+                };
+                if event.id <= replayed_up_to {
+                    continue;
+                }
+                if tx.send(Ok(event)).await.is_err() {
+                    // Subscriber is gone.
+                    break;
+                }
             }
         });
 
@@ -138,25 +281,37 @@ impl<T: BoardsDatabase + Clone> BoardsDatabase for Cached<T> {
     }
 }
 
+// Deleting a stage changes the board too, so its cached entry and events are handled here.
 #[async_trait::async_trait]
-impl<T: TasksDatabase + Clone> TasksDatabase for Cached<T> {
+impl<T: TasksDatabase + BoardsDatabase + Clone> TasksDatabase for Cached<T> {
     async fn create_task(&self, board_id: &str, task: Task) -> CustomResult<Task> {
         let task = self.db.create_task(board_id, task).await?;
-        self.pub_board_updated(board_id)?;
+        self.publish(&BoardEvent::TaskCreated {
+            board_id: ObjectId::from_str(board_id)?,
+            task: task.clone(),
+        })
+        .await?;
         Ok(task)
     }
 
-    async fn read_tasks(&self, board_id: &str) -> CustomResult<Vec<Task>> {
-        self.db.read_tasks(board_id).await
+    async fn read_tasks(
+        &self,
+        board_id: &str,
+        query: &ListQuery,
+        filter: &TaskFilter,
+    ) -> CustomResult<Page<Task>> {
+        self.db.read_tasks(board_id, query, filter).await
     }
 
     async fn read_task(&self, board_id: &str, task_id: &str) -> CustomResult<Task> {
         Ok(match self.cache_get(board_id, task_id).await {
             Ok(t) => {
+                metrics::cache_read("task", true);
                 log::trace!("Read task #{} from cache", task_id);
                 t
             }
             _ => {
+                metrics::cache_read("task", false);
                 log::trace!("Read task #{} from database", task_id);
                 let task = self.db.read_task(board_id, task_id).await?;
                 self.cache_set(board_id, task_id, &task).await?;
@@ -166,16 +321,85 @@ impl<T: TasksDatabase + Clone> TasksDatabase for Cached<T> {
     }
 
     async fn update_task(&self, board_id: &str, task_id: &str, task: Task) -> CustomResult<Task> {
+        let old = self.read_task(board_id, task_id).await?;
         let updated = self.db.update_task(board_id, task_id, task).await?;
         self.cache_set(board_id, task_id, &updated).await?;
-        self.pub_board_updated(board_id)?;
+        self.publish(&BoardEvent::task_updated(
+            ObjectId::from_str(board_id)?,
+            ObjectId::from_str(task_id)?,
+            &old,
+            updated.clone(),
+        )?)
+        .await?;
         Ok(updated)
     }
 
-    async fn delete_task(&self, board_id: &str, task_id: &str) -> CustomResult<Task> {
-        let deleted = self.db.delete_task(board_id, task_id).await?;
+    async fn patch_task(
+        &self,
+        board_id: &str,
+        task_id: &str,
+        version: u64,
+        patch: &TaskPatch,
+    ) -> CustomResult<Task> {
+        let old = self.read_task(board_id, task_id).await?;
+        let patched = self.db.patch_task(board_id, task_id, version, patch).await?;
+        self.cache_set(board_id, task_id, &patched).await?;
+        self.publish(&BoardEvent::task_updated(
+            ObjectId::from_str(board_id)?,
+            ObjectId::from_str(task_id)?,
+            &old,
+            patched.clone(),
+        )?)
+        .await?;
+        Ok(patched)
+    }
+
+    async fn delete_task(&self, board_id: &str, task_id: &str, version: u64) -> CustomResult<Task> {
+        let deleted = self.db.delete_task(board_id, task_id, version).await?;
         self.cache_delete_field(board_id, task_id).await?;
-        self.pub_board_updated(board_id)?;
+        self.publish(&BoardEvent::TaskDeleted {
+            board_id: ObjectId::from_str(board_id)?,
+            task_id: ObjectId::from_str(task_id)?,
+        })
+        .await?;
         Ok(deleted)
     }
+
+    async fn delete_stage(
+        &self,
+        board_id: &str,
+        board: Board,
+        from: &str,
+        to: &str,
+    ) -> CustomResult<Board> {
+        let board_obj_id = ObjectId::from_str(board_id)?;
+        let old = self.read_board(board_id).await?;
+        let updated = self.db.delete_stage(board_id, board, from, to).await?;
+        // Moved tasks are unknown here, so drop all cached entries of the board.
+        self.cache_delete_key(board_id).await?;
+        self.publish(&BoardEvent::board_updated(board_obj_id, &old, updated.clone())?)
+            .await?;
+        self.publish(&BoardEvent::TasksMoved {
+            board_id: board_obj_id,
+            from_stage: from.into(),
+            to_stage: to.into(),
+        })
+        .await?;
+        Ok(updated)
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: UsersDatabase + Clone> UsersDatabase for Cached<T> {
+    async fn create_user(&self, user: User) -> CustomResult<User> {
+        self.db.create_user(user).await
+    }
+
+    async fn read_user(&self, id: &str) -> CustomResult<User> {
+        self.db.read_user(id).await
+    }
+
+    async fn read_user_by_name(&self, username: &str) -> CustomResult<User> {
+        self.db.read_user_by_name(username).await
+    }
 }
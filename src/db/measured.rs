@@ -0,0 +1,139 @@
+use crate::db::{BoardsDatabase, EventMsgReceiver, TasksDatabase, UsersDatabase};
+use crate::errors::CustomResult;
+use crate::metrics;
+use crate::models::{
+    Board, BoardFilter, BoardPatch, ListQuery, Page, Task, TaskFilter, TaskPatch, User,
+};
+use std::future::Future;
+use tracing::Instrument;
+
+/// Measures duration of every call to the storage, labelled with `backend` and the method name,
+/// and traces it as a span.
+#[derive(Clone)]
+pub struct Measured<T: Clone> {
+    db: T,
+    backend: &'static str,
+}
+
+impl<T: Clone> Measured<T> {
+    pub fn new(db: T, backend: &'static str) -> Self {
+        Self { db, backend }
+    }
+
+    async fn call<F: Future>(&self, operation: &'static str, future: F) -> F::Output {
+        let span = tracing::info_span!("db", otel.name = operation, db.system = self.backend);
+        metrics::timed(self.backend, operation, future)
+            .instrument(span)
+            .await
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: BoardsDatabase + Clone> BoardsDatabase for Measured<T> {
+    async fn create_board(&self, board: Board) -> CustomResult<Board> {
+        self.call("create_board", self.db.create_board(board)).await
+    }
+
+    async fn read_boards(
+        &self,
+        query: &ListQuery,
+        filter: &BoardFilter,
+    ) -> CustomResult<Page<Board>> {
+        self.call("read_boards", self.db.read_boards(query, filter)).await
+    }
+
+    async fn read_board(&self, id: &str) -> CustomResult<Board> {
+        self.call("read_board", self.db.read_board(id)).await
+    }
+
+    async fn update_board(&self, id: &str, board: Board) -> CustomResult<Board> {
+        self.call("update_board", self.db.update_board(id, board)).await
+    }
+
+    async fn patch_board(&self, id: &str, version: u64, patch: &BoardPatch) -> CustomResult<Board> {
+        let patched = self.db.patch_board(id, version, patch);
+        self.call("patch_board", patched).await
+    }
+
+    async fn delete_board(&self, id: &str, version: u64) -> CustomResult<Board> {
+        self.call("delete_board", self.db.delete_board(id, version)).await
+    }
+
+    async fn subscribe_on_board_updates(
+        &self,
+        board_id: &str,
+        last_event_id: Option<u64>,
+    ) -> CustomResult<EventMsgReceiver> {
+        let subscribed = self.db.subscribe_on_board_updates(board_id, last_event_id);
+        self.call("subscribe_on_board_updates", subscribed).await
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: TasksDatabase + Clone> TasksDatabase for Measured<T> {
+    async fn create_task(&self, board_id: &str, task: Task) -> CustomResult<Task> {
+        self.call("create_task", self.db.create_task(board_id, task)).await
+    }
+
+    async fn read_tasks(
+        &self,
+        board_id: &str,
+        query: &ListQuery,
+        filter: &TaskFilter,
+    ) -> CustomResult<Page<Task>> {
+        let tasks = self.db.read_tasks(board_id, query, filter);
+        self.call("read_tasks", tasks).await
+    }
+
+    async fn read_task(&self, board_id: &str, task_id: &str) -> CustomResult<Task> {
+        self.call("read_task", self.db.read_task(board_id, task_id)).await
+    }
+
+    async fn update_task(&self, board_id: &str, task_id: &str, task: Task) -> CustomResult<Task> {
+        let updated = self.db.update_task(board_id, task_id, task);
+        self.call("update_task", updated).await
+    }
+
+    async fn patch_task(
+        &self,
+        board_id: &str,
+        task_id: &str,
+        version: u64,
+        patch: &TaskPatch,
+    ) -> CustomResult<Task> {
+        let patched = self.db.patch_task(board_id, task_id, version, patch);
+        self.call("patch_task", patched).await
+    }
+
+    async fn delete_task(&self, board_id: &str, task_id: &str, version: u64) -> CustomResult<Task> {
+        let deleting = self.db.delete_task(board_id, task_id, version);
+        self.call("delete_task", deleting).await
+    }
+
+    async fn delete_stage(
+        &self,
+        board_id: &str,
+        board: Board,
+        from: &str,
+        to: &str,
+    ) -> CustomResult<Board> {
+        let deleted = self.db.delete_stage(board_id, board, from, to);
+        self.call("delete_stage", deleted).await
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: UsersDatabase + Clone> UsersDatabase for Measured<T> {
+    async fn create_user(&self, user: User) -> CustomResult<User> {
+        self.call("create_user", self.db.create_user(user)).await
+    }
+
+    async fn read_user(&self, id: &str) -> CustomResult<User> {
+        self.call("read_user", self.db.read_user(id)).await
+    }
+
+    async fn read_user_by_name(&self, username: &str) -> CustomResult<User> {
+        let user = self.db.read_user_by_name(username);
+        self.call("read_user_by_name", user).await
+    }
+}
@@ -0,0 +1,532 @@
+use crate::db::{
+    check_version, BoardsDatabase, EventMsgReceiver, EventMsgResult, TasksDatabase, UsersDatabase,
+};
+use crate::errors::{CustomError, CustomResult};
+use crate::events::{self, BoardEvent, StoredEvent};
+use crate::models::{
+    Board, BoardFilter, BoardPatch, ListQuery, Listable, Page, SortOrder, Task, TaskFilter,
+    TaskPatch, User,
+};
+use crate::rank;
+use crate::request_id;
+use mongodb::bson::oid::ObjectId;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::sync::{broadcast, mpsc};
+
+/// In-process storage. Keeps everything in memory, so data is lost on restart.
+#[derive(Clone)]
+pub struct Memory {
+    state: Arc<RwLock<State>>,
+    /// Number of the latest events kept per board for replay.
+    event_log_size: usize,
+}
+
+#[derive(Default)]
+struct State {
+    boards: BTreeMap<ObjectId, Board>,
+    tasks: BTreeMap<ObjectId, Task>,
+    users: BTreeMap<ObjectId, User>,
+    events: HashMap<ObjectId, EventLog>,
+}
+
+/// Latest events of a board and channel delivering new ones.
+struct EventLog {
+    last_id: u64,
+    events: VecDeque<StoredEvent>,
+    channel: broadcast::Sender<StoredEvent>,
+}
+
+impl EventLog {
+    fn new() -> Self {
+        Self {
+            last_id: 0,
+            events: VecDeque::new(),
+            channel: broadcast::channel(100).0,
+        }
+    }
+}
+
+impl Memory {
+    pub fn new(event_log_size: usize) -> Self {
+        Self {
+            state: Arc::default(),
+            event_log_size,
+        }
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, State> {
+        self.state.read().expect("Memory storage lock poisoned")
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, State> {
+        self.state.write().expect("Memory storage lock poisoned")
+    }
+
+    fn board_not_found(id: &str) -> CustomError {
+        CustomError::NotFound(format!("board with id: {}", id))
+    }
+
+    fn task_not_found(id: &str) -> CustomError {
+        CustomError::NotFound(format!("task with id: {}", id))
+    }
+
+    fn user_not_found(id: &str) -> CustomError {
+        CustomError::NotFound(format!("user: {}", id))
+    }
+
+    fn paginate<'a, T, I>(items: I, query: &ListQuery) -> CustomResult<Page<T>>
+    where
+        T: Listable + Clone + 'a,
+        I: Iterator<Item = &'a T>,
+    {
+        let cursor = query.cursor()?;
+        let prefix = query.name_prefix.as_deref().unwrap_or_default();
+        let mut items: Vec<T> = items
+            .filter(|item| item.name().starts_with(prefix))
+            .filter(|item| match &cursor {
+                Some(cursor) => {
+                    let key = cursor.key.as_deref();
+                    Self::compare(*item, key, Some(cursor.id), query) == Ordering::Greater
+                }
+                None => true,
+            })
+            .cloned()
+            .collect();
+
+        items.sort_by(|a, b| Self::compare(a, b.sort_key(query.sort()), b.id(), query));
+        items.truncate(query.limit() + 1);
+        Ok(Page::new(items, query))
+    }
+
+    /// Compares item with another one, given by sort key and id, in listing order.
+    fn compare<T: Listable>(
+        item: &T,
+        key: Option<&str>,
+        id: Option<ObjectId>,
+        query: &ListQuery,
+    ) -> Ordering {
+        let item_key = item.sort_key(query.sort());
+        let ordering = item_key.cmp(&key).then_with(|| item.id().cmp(&id));
+        match query.order {
+            SortOrder::Asc => ordering,
+            SortOrder::Desc => ordering.reverse(),
+        }
+    }
+
+    fn publish(&self, state: &mut State, event: BoardEvent) {
+        let log = state
+            .events
+            .entry(*event.board_id())
+            .or_insert_with(EventLog::new);
+        log.last_id += 1;
+        let event = StoredEvent {
+            id: log.last_id,
+            request_id: request_id::current(),
+            event,
+        };
+
+        log.events.push_back(event.clone());
+        while log.events.len() > self.event_log_size {
+            log.events.pop_front();
+        }
+
+        // Error means there are no subscribers.
+        let _ = log.channel.send(event);
+    }
+}
+
+#[async_trait::async_trait]
+impl BoardsDatabase for Memory {
+    async fn create_board(&self, mut board: Board) -> CustomResult<Board> {
+        let id = ObjectId::new();
+        board.id = Some(id);
+        board.version = 1;
+        self.write().boards.insert(id, board.clone());
+        Ok(board)
+    }
+
+    async fn read_boards(
+        &self,
+        query: &ListQuery,
+        filter: &BoardFilter,
+    ) -> CustomResult<Page<Board>> {
+        let state = self.read();
+        let boards = state.boards.values().filter(|board| {
+            let member = filter.member.as_ref();
+            member.is_none_or(|user_id| board.role_of(user_id).is_some())
+        });
+        Self::paginate(boards, query)
+    }
+
+    async fn read_board(&self, id: &str) -> CustomResult<Board> {
+        let obj_id = ObjectId::from_str(id)?;
+        let state = self.read();
+        let board = state.boards.get(&obj_id);
+        board.cloned().ok_or_else(|| Self::board_not_found(id))
+    }
+
+    async fn update_board(&self, id: &str, mut board: Board) -> CustomResult<Board> {
+        let obj_id = ObjectId::from_str(id)?;
+        let mut state = self.write();
+        let stored = state
+            .boards
+            .get_mut(&obj_id)
+            .ok_or_else(|| Self::board_not_found(id))?;
+        check_version(stored.version, board.version, &format!("board {}", id))?;
+        board.id = Some(obj_id);
+        board.version += 1;
+        let old = std::mem::replace(stored, board.clone());
+        let event = BoardEvent::board_updated(obj_id, &old, board.clone())?;
+        self.publish(&mut state, event);
+        Ok(board)
+    }
+
+    async fn patch_board(&self, id: &str, version: u64, patch: &BoardPatch) -> CustomResult<Board> {
+        let obj_id = ObjectId::from_str(id)?;
+        let mut state = self.write();
+        let stored = state
+            .boards
+            .get_mut(&obj_id)
+            .ok_or_else(|| Self::board_not_found(id))?;
+        check_version(stored.version, version, &format!("board {}", id))?;
+        let old = stored.clone();
+        patch.apply(stored);
+        stored.version += 1;
+        let board = stored.clone();
+        let event = BoardEvent::board_updated(obj_id, &old, board.clone())?;
+        self.publish(&mut state, event);
+        Ok(board)
+    }
+
+    async fn delete_board(&self, id: &str, version: u64) -> CustomResult<Board> {
+        let obj_id = ObjectId::from_str(id)?;
+        let mut state = self.write();
+        let stored = state
+            .boards
+            .get(&obj_id)
+            .ok_or_else(|| Self::board_not_found(id))?;
+        check_version(stored.version, version, &format!("board {}", id))?;
+        let board = state.boards.remove(&obj_id).expect("Board is checked above");
+
+        // Delete all board's tasks.
+        state.tasks.retain(|_, task| task.board_id != Some(obj_id));
+
+        self.publish(&mut state, BoardEvent::BoardDeleted { board_id: obj_id });
+        state.events.remove(&obj_id);
+        Ok(board)
+    }
+
+    async fn subscribe_on_board_updates(
+        &self,
+        board_id: &str,
+        last_event_id: Option<u64>,
+    ) -> CustomResult<EventMsgReceiver> {
+        let obj_id = ObjectId::from_str(board_id)?;
+        // Log is read and subscribed under the same lock, so no event is missed or repeated.
+        let (missed, mut board_events) = {
+            let mut state = self.write();
+            if !state.boards.contains_key(&obj_id) {
+                return Err(Self::board_not_found(board_id));
+            }
+            let log = state.events.entry(obj_id).or_insert_with(EventLog::new);
+            let missed = match last_event_id {
+                Some(last_id) => {
+                    let logged = log.events.iter().cloned().collect();
+                    events::replay(logged, obj_id, last_id)
+                }
+                None => Vec::new(),
+            };
+            (missed, log.channel.subscribe())
+        };
+
+        let (tx, rx) = mpsc::channel::<EventMsgResult>(100);
+        tokio::spawn(async move {
+            for event in missed {
+                if tx.send(Ok(event)).await.is_err() {
+                    return;
+                }
+            }
+
+            loop {
+                let event = match board_events.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        // Client resumes from the last received event after reconnect.
+                        log::warn!("Board events subscriber skipped {} events", skipped);
+                        break;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                if tx.send(Ok(event)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(rx)
+    }
+}
+
+#[async_trait::async_trait]
+impl TasksDatabase for Memory {
+    async fn create_task(&self, board_id: &str, mut task: Task) -> CustomResult<Task> {
+        let board_obj_id = ObjectId::from_str(board_id)?;
+        let mut state = self.write();
+        if !state.boards.contains_key(&board_obj_id) {
+            return Err(Self::board_not_found(board_id));
+        }
+
+        let id = ObjectId::new();
+        task.id = Some(id);
+        task.board_id = Some(board_obj_id);
+        task.version = 1;
+        state.tasks.insert(id, task.clone());
+        let event = BoardEvent::TaskCreated {
+            board_id: board_obj_id,
+            task: task.clone(),
+        };
+        self.publish(&mut state, event);
+        Ok(task)
+    }
+
+    async fn read_tasks(
+        &self,
+        board_id: &str,
+        query: &ListQuery,
+        filter: &TaskFilter,
+    ) -> CustomResult<Page<Task>> {
+        let board_obj_id = ObjectId::from_str(board_id)?;
+        let state = self.read();
+        let tasks = state.tasks.values();
+        let board_tasks = tasks
+            .filter(|task| task.board_id == Some(board_obj_id))
+            .filter(|task| filter.stage.as_ref().is_none_or(|stage| &task.stage == stage));
+        Self::paginate(board_tasks, query)
+    }
+
+    async fn read_task(&self, _: &str, task_id: &str) -> CustomResult<Task> {
+        let obj_id = ObjectId::from_str(task_id)?;
+        let state = self.read();
+        let task = state.tasks.get(&obj_id);
+        task.cloned().ok_or_else(|| Self::task_not_found(task_id))
+    }
+
+    async fn update_task(&self, board_id: &str, task_id: &str, mut task: Task) -> CustomResult<Task> {
+        let board_obj_id = ObjectId::from_str(board_id)?;
+        let task_obj_id = ObjectId::from_str(task_id)?;
+        let mut state = self.write();
+        let stored = state
+            .tasks
+            .get_mut(&task_obj_id)
+            .ok_or_else(|| Self::task_not_found(task_id))?;
+        check_version(stored.version, task.version, &format!("task {}", task_id))?;
+        task.id = Some(task_obj_id);
+        task.board_id = Some(board_obj_id);
+        task.version += 1;
+        let old = std::mem::replace(stored, task.clone());
+        let event = BoardEvent::task_updated(board_obj_id, task_obj_id, &old, task.clone())?;
+        self.publish(&mut state, event);
+        Ok(task)
+    }
+
+    async fn patch_task(
+        &self,
+        board_id: &str,
+        task_id: &str,
+        version: u64,
+        patch: &TaskPatch,
+    ) -> CustomResult<Task> {
+        let board_obj_id = ObjectId::from_str(board_id)?;
+        let task_obj_id = ObjectId::from_str(task_id)?;
+        let mut state = self.write();
+        let stored = state
+            .tasks
+            .get_mut(&task_obj_id)
+            .filter(|task| task.board_id == Some(board_obj_id))
+            .ok_or_else(|| Self::task_not_found(task_id))?;
+        check_version(stored.version, version, &format!("task {}", task_id))?;
+        let old = stored.clone();
+        patch.apply(stored);
+        stored.version += 1;
+        let task = stored.clone();
+        let event = BoardEvent::task_updated(board_obj_id, task_obj_id, &old, task.clone())?;
+        self.publish(&mut state, event);
+        Ok(task)
+    }
+
+    async fn delete_task(&self, _: &str, task_id: &str, version: u64) -> CustomResult<Task> {
+        let obj_id = ObjectId::from_str(task_id)?;
+        let mut state = self.write();
+        let stored = state
+            .tasks
+            .get(&obj_id)
+            .ok_or_else(|| Self::task_not_found(task_id))?;
+        check_version(stored.version, version, &format!("task {}", task_id))?;
+        let task = state.tasks.remove(&obj_id).expect("Task is checked above");
+        if let Some(board_id) = task.board_id {
+            let event = BoardEvent::TaskDeleted {
+                board_id,
+                task_id: obj_id,
+            };
+            self.publish(&mut state, event);
+        }
+        Ok(task)
+    }
+
+    async fn delete_stage(
+        &self,
+        board_id: &str,
+        mut board: Board,
+        from: &str,
+        to: &str,
+    ) -> CustomResult<Board> {
+        let board_obj_id = ObjectId::from_str(board_id)?;
+        let mut state = self.write();
+        let stored = state
+            .boards
+            .get_mut(&board_obj_id)
+            .ok_or_else(|| Self::board_not_found(board_id))?;
+        check_version(stored.version, board.version, &format!("board {}", board_id))?;
+        board.id = Some(board_obj_id);
+        board.version += 1;
+        let old = std::mem::replace(stored, board.clone());
+
+        let board_tasks = state
+            .tasks
+            .values()
+            .filter(|task| task.board_id == Some(board_obj_id));
+        let last = board_tasks
+            .clone()
+            .filter(|task| task.stage == to)
+            .map(|task| (&task.rank, task.id))
+            .max()
+            .map(|(rank, _)| rank.clone());
+        let mut moved: Vec<(String, ObjectId)> = board_tasks
+            .filter(|task| task.stage == from)
+            .filter_map(|task| Some((task.rank.clone(), task.id?)))
+            .collect();
+        moved.sort();
+        let ranks = rank::after(last.as_deref(), moved.len())?;
+        for ((_, id), rank) in moved.into_iter().zip(ranks) {
+            if let Some(task) = state.tasks.get_mut(&id) {
+                task.stage = to.into();
+                task.rank = rank;
+                task.version += 1;
+            }
+        }
+
+        let event = BoardEvent::board_updated(board_obj_id, &old, board.clone())?;
+        self.publish(&mut state, event);
+        let event = BoardEvent::TasksMoved {
+            board_id: board_obj_id,
+            from_stage: from.into(),
+            to_stage: to.into(),
+        };
+        self.publish(&mut state, event);
+        Ok(board)
+    }
+}
+
+#[async_trait::async_trait]
+impl UsersDatabase for Memory {
+    async fn create_user(&self, mut user: User) -> CustomResult<User> {
+        let mut state = self.write();
+        if state.users.values().any(|u| u.username == user.username) {
+            return Err(CustomError::Conflict(format!(
+                "username is taken: {}",
+                user.username
+            )));
+        }
+        let id = ObjectId::new();
+        user.id = Some(id);
+        state.users.insert(id, user.clone());
+        Ok(user)
+    }
+
+    async fn read_user(&self, id: &str) -> CustomResult<User> {
+        let obj_id = ObjectId::from_str(id)?;
+        let state = self.read();
+        let user = state.users.get(&obj_id);
+        user.cloned().ok_or_else(|| Self::user_not_found(id))
+    }
+
+    async fn read_user_by_name(&self, username: &str) -> CustomResult<User> {
+        let state = self.read();
+        let user = state.users.values().find(|u| u.username == username);
+        user.cloned().ok_or_else(|| Self::user_not_found(username))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Creates a board and renames it `count` times, logging an event each time.
+    async fn renamed_board(memory: &Memory, count: usize) -> String {
+        let board = serde_json::json!({"name": "Release", "description": ""});
+        let board = memory.create_board(serde_json::from_value(board).unwrap()).await.unwrap();
+        let id = board.id.unwrap().to_hex();
+        for i in 0..count {
+            let patch = BoardPatch {
+                name: Some(format!("Release {}", i)),
+                description: None,
+            };
+            memory.patch_board(&id, i as u64 + 1, &patch).await.unwrap();
+        }
+        id
+    }
+
+    /// Receives events until none comes for a while.
+    async fn received(mut events: EventMsgReceiver) -> Vec<StoredEvent> {
+        let mut received = Vec::new();
+        let timeout = Duration::from_millis(100);
+        while let Ok(Some(event)) = tokio::time::timeout(timeout, events.recv()).await {
+            received.push(event.unwrap());
+        }
+        received
+    }
+
+    fn ids(events: &[StoredEvent]) -> Vec<u64> {
+        events.iter().map(|event| event.id).collect()
+    }
+
+    #[actix_rt::test]
+    async fn replays_events_after_last_id() {
+        let memory = Memory::new(16);
+        let id = renamed_board(&memory, 5).await;
+
+        let events = memory.subscribe_on_board_updates(&id, Some(2)).await.unwrap();
+        let patch = BoardPatch {
+            name: Some("Live".into()),
+            description: None,
+        };
+        memory.patch_board(&id, 6, &patch).await.unwrap();
+        let events = received(events).await;
+        assert_eq!(ids(&events), [3, 4, 5, 6]);
+        assert!(matches!(events[0].event, BoardEvent::BoardUpdated { .. }));
+
+        let events = memory.subscribe_on_board_updates(&id, Some(6)).await.unwrap();
+        assert!(received(events).await.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn resyncs_once_missed_events_are_trimmed() {
+        let memory = Memory::new(3);
+        let id = renamed_board(&memory, 6).await;
+
+        let events = memory.subscribe_on_board_updates(&id, Some(1)).await.unwrap();
+        let events = received(events).await;
+        assert_eq!(ids(&events), [3, 4, 5, 6]);
+        assert!(matches!(events[0].event, BoardEvent::Resync { .. }));
+
+        // The log still has every event after 3.
+        let events = memory.subscribe_on_board_updates(&id, Some(3)).await.unwrap();
+        let events = received(events).await;
+        assert_eq!(ids(&events), [4, 5, 6]);
+        assert!(matches!(events[0].event, BoardEvent::BoardUpdated { .. }));
+    }
+}
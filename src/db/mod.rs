@@ -1,30 +1,95 @@
 pub mod cached;
+pub mod measured;
+pub mod memory;
 pub mod mongo;
+pub mod sql;
 
-use crate::errors::CustomResult;
-use crate::models::{Board, Task};
-use actix_web::web::Bytes;
+use crate::errors::{CustomError, CustomResult};
+use crate::events::StoredEvent;
+use crate::models::{
+    Board, BoardFilter, BoardPatch, ListQuery, Page, Task, TaskFilter, TaskPatch, User,
+};
 use tokio::sync::mpsc::Receiver;
 
-pub type EventMsgResult = CustomResult<Bytes>;
+pub type EventMsgResult = CustomResult<StoredEvent>;
 pub type EventMsgReceiver = Receiver<EventMsgResult>;
 
 #[async_trait::async_trait]
 pub trait BoardsDatabase: Send + Sync {
     async fn create_board(&self, board: Board) -> CustomResult<Board>;
-    async fn read_boards(&self) -> CustomResult<Vec<Board>>;
+    async fn read_boards(
+        &self,
+        query: &ListQuery,
+        filter: &BoardFilter,
+    ) -> CustomResult<Page<Board>>;
     async fn read_board(&self, id: &str) -> CustomResult<Board>;
+    /// Stores board with the next version. `board.version` must be the stored one,
+    /// otherwise fails with `PreconditionFailed`.
     async fn update_board(&self, id: &str, board: Board) -> CustomResult<Board>;
-    async fn delete_board(&self, id: &str) -> CustomResult<Board>;
+    /// Changes only fields set in the patch, checking `version` like [`Self::update_board`].
+    async fn patch_board(&self, id: &str, version: u64, patch: &BoardPatch) -> CustomResult<Board>;
+    /// Deletes board with its tasks, checking `version` like [`Self::update_board`].
+    async fn delete_board(&self, id: &str, version: u64) -> CustomResult<Board>;
 
-    async fn subscribe_on_board_updates(&self, board_id: &str) -> CustomResult<EventMsgReceiver>;
+    /// Streams board events. If `last_event_id` is set, missed events after it are replayed first.
+    async fn subscribe_on_board_updates(
+        &self,
+        board_id: &str,
+        last_event_id: Option<u64>,
+    ) -> CustomResult<EventMsgReceiver>;
 }
 
 #[async_trait::async_trait]
 pub trait TasksDatabase: Send + Sync {
     async fn create_task(&self, board_id: &str, task: Task) -> CustomResult<Task>;
-    async fn read_tasks(&self, board_id: &str) -> CustomResult<Vec<Task>>;
+    async fn read_tasks(
+        &self,
+        board_id: &str,
+        query: &ListQuery,
+        filter: &TaskFilter,
+    ) -> CustomResult<Page<Task>>;
     async fn read_task(&self, board_id: &str, task_id: &str) -> CustomResult<Task>;
+    /// Stores task with the next version, see [`BoardsDatabase::update_board`].
     async fn update_task(&self, board_id: &str, task_id: &str, task: Task) -> CustomResult<Task>;
-    async fn delete_task(&self, board_id: &str, task_id: &str) -> CustomResult<Task>;
+    /// Changes only fields set in the patch, see [`BoardsDatabase::patch_board`].
+    async fn patch_task(
+        &self,
+        board_id: &str,
+        task_id: &str,
+        version: u64,
+        patch: &TaskPatch,
+    ) -> CustomResult<Task>;
+    /// Deletes task, checking `version` like [`BoardsDatabase::update_board`].
+    async fn delete_task(&self, board_id: &str, task_id: &str, version: u64) -> CustomResult<Task>;
+
+    /// Stores board without its `from` stage, checking its version like
+    /// [`BoardsDatabase::update_board`], and moves the stage's tasks to the end of `to` stage,
+    /// increasing their versions. Both are done at once: no task is moved if the board isn't stored.
+    async fn delete_stage(
+        &self,
+        board_id: &str,
+        board: Board,
+        from: &str,
+        to: &str,
+    ) -> CustomResult<Board>;
+}
+
+#[async_trait::async_trait]
+pub trait UsersDatabase: Send + Sync {
+    /// Fails with `Conflict` if username is already taken.
+    async fn create_user(&self, user: User) -> CustomResult<User>;
+    async fn read_user(&self, id: &str) -> CustomResult<User>;
+    async fn read_user_by_name(&self, username: &str) -> CustomResult<User>;
+}
+
+/// Checks that entity wasn't changed since it was read for an update.
+fn check_version(stored: u64, expected: u64, entity: &str) -> CustomResult<()> {
+    if stored == expected {
+        Ok(())
+    } else {
+        Err(CustomError::PreconditionFailed(format!(
+            "{} was modified concurrently, version {} is stored",
+            entity, stored
+        )))
+    }
 }
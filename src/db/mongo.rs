@@ -1,22 +1,49 @@
-use crate::db::{BoardsDatabase, EventMsgReceiver, TasksDatabase};
+use crate::db::{check_version, BoardsDatabase, EventMsgReceiver, TasksDatabase, UsersDatabase};
 use crate::errors::{CustomError, CustomResult};
-use crate::models::{Board, Task};
+use crate::models::{
+    Board, BoardFilter, BoardPatch, ListQuery, Listable, Page, SortField, SortOrder, Task,
+    TaskFilter, TaskPatch, User,
+};
+use crate::rank;
 use mongodb::{
-    bson::{doc, oid::ObjectId, ser, Bson},
-    Client, Collection,
+    bson::{doc, oid::ObjectId, ser, Bson, Document},
+    error::{ErrorKind, WriteFailure},
+    options::{
+        FindOneAndUpdateOptions, FindOneOptions, FindOptions, IndexOptions, ReturnDocument,
+    },
+    Client, Collection, IndexModel,
 };
 use serde::de::DeserializeOwned;
+use std::collections::BTreeSet;
 use std::str::FromStr;
 use tokio_stream::StreamExt;
 
 #[derive(Debug, Clone)]
 pub struct Mongo {
     client: Client,
+    /// Whether the server is a replica set member or `mongos`, which support transactions.
+    transactions: bool,
 }
 
 impl Mongo {
     pub fn new(client: Client) -> Self {
-        Self { client }
+        Self {
+            client,
+            transactions: false,
+        }
+    }
+
+    /// Asks the server whether it supports transactions. A standalone server doesn't,
+    /// so changes which need them are done one by one.
+    pub async fn detect_transactions(&mut self) -> CustomResult<()> {
+        let admin = self.client.database("admin");
+        let reply = admin.run_command(doc! { "isMaster": 1 }, None).await?;
+        let mongos = reply.get_str("msg") == Ok("isdbgrid");
+        self.transactions = reply.contains_key("setName") || mongos;
+        if !self.transactions {
+            log::warn!("Standalone MongoDB: tasks of deleted stages are moved without a transaction");
+        }
+        Ok(())
     }
 
     pub fn get_boards_collection(&self) -> Collection<Board> {
@@ -27,6 +54,64 @@ impl Mongo {
         self.client.database("boards_back").collection("tasks")
     }
 
+    pub fn get_users_collection(&self) -> Collection<User> {
+        self.client.database("boards_back").collection("users")
+    }
+
+    /// Creates indexes the storage relies on. Does nothing for already existing ones.
+    pub async fn create_indexes(&self) -> CustomResult<()> {
+        let unique_username = IndexModel::builder()
+            .keys(doc! { "username": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        self.get_users_collection()
+            .create_index(unique_username, None)
+            .await?;
+
+        let boards_by_member = IndexModel::builder()
+            .keys(doc! { "members.user_id": 1 })
+            .build();
+        self.get_boards_collection()
+            .create_index(boards_by_member, None)
+            .await?;
+        Ok(())
+    }
+
+    /// Ranks tasks stored before manual ordering, which have no rank. Every stage having such
+    /// tasks is ranked anew in its current order, legacy tasks first, like the SQL migration does.
+    pub async fn rank_legacy_tasks(&self) -> CustomResult<()> {
+        let collection = self.get_tasks_collection();
+        let legacy = doc! { "$or": [{ "rank": { "$exists": false } }, { "rank": "" }] };
+        let mut cursor = collection.find(legacy, None).await?;
+        let mut stages = BTreeSet::new();
+        while let Some(task) = cursor.next().await {
+            let task = task?;
+            stages.extend(task.board_id.map(|board_id| (board_id, task.stage)));
+        }
+
+        let mut ranked = 0;
+        for (board_id, stage) in stages {
+            let options = FindOptions::builder()
+                .sort(doc! { "rank": 1, "_id": 1 })
+                .build();
+            let query = doc! { "board_id": &board_id, "stage": &stage };
+            let mut ids = Vec::new();
+            let mut cursor = collection.find(query, options).await?;
+            while let Some(task) = cursor.next().await {
+                ids.extend(task?.id);
+            }
+            for (position, id) in ids.iter().enumerate() {
+                let update = patch_update(doc! { "rank": rank::nth(position) });
+                collection.update_one(doc! { "_id": id }, update, None).await?;
+            }
+            ranked += ids.len();
+        }
+        if ranked > 0 {
+            log::info!("Ranked {} tasks of stages having tasks without rank", ranked);
+        }
+        Ok(())
+    }
+
     async fn get_by_id<T>(&self, collection: Collection<T>, id: Bson) -> CustomResult<T>
     where
         T: DeserializeOwned + Unpin + Send + Sync,
@@ -35,27 +120,127 @@ impl Mongo {
         let board = collection.find_one(query, None).await?;
         board.ok_or_else(|| CustomError::NotFound(format!("board with id: {}", id)))
     }
+
+    /// Finds one page of documents matching `filter`.
+    async fn find_page<T>(
+        &self,
+        collection: Collection<T>,
+        filter: Document,
+        query: &ListQuery,
+    ) -> CustomResult<Page<T>>
+    where
+        T: Listable + DeserializeOwned + Unpin + Send + Sync,
+    {
+        let mut conditions = vec![filter];
+        if let Some(prefix) = &query.name_prefix {
+            let pattern = format!("^{}", escape_regex(prefix));
+            conditions.push(doc! { "name": { "$regex": pattern } });
+        }
+
+        let (direction, after) = match query.order {
+            SortOrder::Asc => (1, "$gt"),
+            SortOrder::Desc => (-1, "$lt"),
+        };
+        let key_field = match query.sort() {
+            SortField::Id => None,
+            SortField::Name => Some("name"),
+            SortField::Rank => Some("rank"),
+        };
+
+        if let Some(cursor) = query.cursor()? {
+            conditions.push(match (key_field, cursor.key) {
+                (Some(field), Some(key)) => doc! { "$or": [
+                    { field: { after: &key } },
+                    { field: &key, "_id": { after: cursor.id } },
+                ] },
+                _ => doc! { "_id": { after: cursor.id } },
+            });
+        }
+
+        let sort = match key_field {
+            None => doc! { "_id": direction },
+            Some(field) => doc! { field: direction, "_id": direction },
+        };
+        let options = FindOptions::builder()
+            .sort(sort)
+            .limit((query.limit() + 1) as i64)
+            .build();
+
+        let mut cursor = collection
+            .find(doc! { "$and": conditions }, options)
+            .await?;
+
+        let mut items = Vec::new();
+        while let Some(item) = cursor.next().await {
+            items.push(item?);
+        }
+        Ok(Page::new(items, query))
+    }
+}
+
+fn is_duplicate_key(error: &mongodb::error::Error) -> bool {
+    matches!(
+        error.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(e)) if e.code == 11000
+    )
+}
+
+/// Matches document with the id and version. Documents stored before versioning have none.
+fn version_filter(id: &ObjectId, version: u64) -> Document {
+    if version == 0 {
+        doc! { "_id": id, "$or": [{ "version": 0_i64 }, { "version": { "$exists": false } }] }
+    } else {
+        doc! { "_id": id, "version": version as i64 }
+    }
+}
+
+/// Sets only the given fields and increases version.
+fn patch_update(fields: Document) -> Document {
+    doc! { "$set": fields, "$inc": { "version": 1_i64 } }
+}
+
+/// Makes `find_one_and_update` return the document after the update.
+fn updated_document() -> FindOneAndUpdateOptions {
+    FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build()
+}
+
+fn escape_regex(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
 }
 
 #[async_trait::async_trait]
 impl BoardsDatabase for Mongo {
-    async fn create_board(&self, board: Board) -> CustomResult<Board> {
+    async fn create_board(&self, mut board: Board) -> CustomResult<Board> {
         let collection = self.get_boards_collection();
+        board.version = 1;
         let insert_result = collection.insert_one(board, None).await?;
         self.get_by_id(collection, insert_result.inserted_id).await
     }
 
-    async fn read_boards(&self) -> CustomResult<Vec<Board>> {
+    async fn read_boards(
+        &self,
+        query: &ListQuery,
+        filter: &BoardFilter,
+    ) -> CustomResult<Page<Board>> {
         let collection = self.get_boards_collection();
-        let query = doc! {};
-        let mut boards = collection.find(query, None).await?;
-
-        let mut boards_vec = Vec::new();
-        while let Some(board) = boards.next().await {
-            boards_vec.push(board?);
-        }
-
-        Ok(boards_vec)
+        let boards_filter = match &filter.member {
+            // Creator of a board without members is its owner, see `Board::migrate_members`.
+            Some(user_id) => doc! { "$or": [
+                { "members.user_id": user_id },
+                { "members.0": { "$exists": false }, "created_by": user_id },
+            ] },
+            None => doc! {},
+        };
+        self.find_page(collection, boards_filter, query).await
     }
 
     async fn read_board(&self, id: &str) -> CustomResult<Board> {
@@ -64,30 +249,65 @@ impl BoardsDatabase for Mongo {
         self.get_by_id(collection, obj_id.into()).await
     }
 
-    async fn update_board(&self, id: &str, board: Board) -> CustomResult<Board> {
+    async fn update_board(&self, id: &str, mut board: Board) -> CustomResult<Board> {
         let obj_id = ObjectId::from_str(id)?;
         let collection = self.get_boards_collection();
-        let query = doc! { "_id": &obj_id };
+        let query = version_filter(&obj_id, board.version);
+        board.id = None;
+        board.version += 1;
         let update = doc! { "$set": ser::to_bson(&board)? };
-        collection.update_one(query, update, None).await?;
+        let result = collection.update_one(query, update, None).await?;
+        if result.matched_count == 0 {
+            let stored = self.read_board(id).await?;
+            check_version(stored.version, board.version - 1, &format!("board {}", id))?;
+        }
+        board.id = Some(obj_id);
         Ok(board)
     }
 
-    async fn delete_board(&self, id: &str) -> CustomResult<Board> {
+    async fn patch_board(&self, id: &str, version: u64, patch: &BoardPatch) -> CustomResult<Board> {
         let obj_id = ObjectId::from_str(id)?;
+        let fields = ser::to_document(patch)?;
         let collection = self.get_boards_collection();
-        let query = doc! { "_id": &obj_id };
-        let board = collection.find_one_and_delete(query, None).await?;
+        let query = version_filter(&obj_id, version);
+        let board = collection
+            .find_one_and_update(query, patch_update(fields), updated_document())
+            .await?;
+        match board {
+            Some(board) => Ok(board),
+            None => {
+                let stored = self.read_board(id).await?;
+                check_version(stored.version, version, &format!("board {}", id))?;
+                Err(CustomError::InternalError(format!("board {} wasn't patched", id)))
+            }
+        }
+    }
+
+    async fn delete_board(&self, id: &str, version: u64) -> CustomResult<Board> {
+        let obj_id = ObjectId::from_str(id)?;
+        let collection = self.get_boards_collection();
+        let query = version_filter(&obj_id, version);
+        let board = match collection.find_one_and_delete(query, None).await? {
+            Some(board) => board,
+            None => {
+                let stored = self.read_board(id).await?;
+                check_version(stored.version, version, &format!("board {}", id))?;
+                return Err(CustomError::InternalError(format!("board {} wasn't deleted", id)));
+            }
+        };
 
         // Delete all board's tasks.
         let tasks_collection = self.get_tasks_collection();
         let query = doc! { "board_id": &obj_id };
         tasks_collection.delete_many(query, None).await?;
-
-        board.ok_or_else(|| CustomError::NotFound(format!("board with id: {}", id)))
+        Ok(board)
     }
 
-    async fn subscribe_on_board_updates(&self, _board_id: &str) -> CustomResult<EventMsgReceiver> {
+    async fn subscribe_on_board_updates(
+        &self,
+        _board_id: &str,
+        _last_event_id: Option<u64>,
+    ) -> CustomResult<EventMsgReceiver> {
         Err(CustomError::InternalError(
             "Subscription isn't implemented for mongo".into(),
         ))
@@ -99,21 +319,24 @@ impl TasksDatabase for Mongo {
     async fn create_task(&self, board_id: &str, mut task: Task) -> CustomResult<Task> {
         let collection = self.get_tasks_collection();
         task.board_id = Some(ObjectId::from_str(board_id)?);
+        task.version = 1;
         let insert_result = collection.insert_one(task, None).await?;
         self.get_by_id(collection, insert_result.inserted_id).await
     }
 
-    async fn read_tasks(&self, board_id: &str) -> CustomResult<Vec<Task>> {
+    async fn read_tasks(
+        &self,
+        board_id: &str,
+        query: &ListQuery,
+        filter: &TaskFilter,
+    ) -> CustomResult<Page<Task>> {
         let collection = self.get_tasks_collection();
         let board_obj_id = ObjectId::from_str(board_id)?;
-        let query = doc! { "board_id": &board_obj_id };
-        let mut tasks = collection.find(query, None).await?;
-
-        let mut tasks_vec = Vec::new();
-        while let Some(task) = tasks.next().await {
-            tasks_vec.push(task?);
+        let mut tasks_filter = doc! { "board_id": &board_obj_id };
+        if let Some(stage) = &filter.stage {
+            tasks_filter.insert("stage", stage);
         }
-        Ok(tasks_vec)
+        self.find_page(collection, tasks_filter, query).await
     }
 
     async fn read_task(&self, _: &str, id: &str) -> CustomResult<Task> {
@@ -126,17 +349,204 @@ impl TasksDatabase for Mongo {
         let task_obj_id = ObjectId::from_str(task_id)?;
         task.board_id = Some(ObjectId::from_str(board_id)?);
         let collection = self.get_tasks_collection();
-        let query = doc! { "_id": &task_obj_id };
+        let query = version_filter(&task_obj_id, task.version);
+        task.id = None;
+        task.version += 1;
         let update = doc! { "$set": ser::to_bson(&task)? };
-        collection.update_one(query, update, None).await?;
+        let result = collection.update_one(query, update, None).await?;
+        if result.matched_count == 0 {
+            let stored = self.read_task(board_id, task_id).await?;
+            check_version(stored.version, task.version - 1, &format!("task {}", task_id))?;
+        }
+        task.id = Some(task_obj_id);
         Ok(task)
     }
 
-    async fn delete_task(&self, _: &str, id: &str) -> CustomResult<Task> {
+    async fn patch_task(
+        &self,
+        board_id: &str,
+        task_id: &str,
+        version: u64,
+        patch: &TaskPatch,
+    ) -> CustomResult<Task> {
+        let task_obj_id = ObjectId::from_str(task_id)?;
+        let board_obj_id = ObjectId::from_str(board_id)?;
+        let fields = ser::to_document(patch)?;
+        let collection = self.get_tasks_collection();
+        let mut query = version_filter(&task_obj_id, version);
+        query.insert("board_id", board_obj_id);
+        let task = collection
+            .find_one_and_update(query, patch_update(fields), updated_document())
+            .await?;
+        match task {
+            Some(task) => Ok(task),
+            None => {
+                let stored = self.read_task(board_id, task_id).await?;
+                if stored.board_id != Some(board_obj_id) {
+                    return Err(CustomError::NotFound(format!("task with id: {}", task_id)));
+                }
+                check_version(stored.version, version, &format!("task {}", task_id))?;
+                Err(CustomError::InternalError(format!("task {} wasn't patched", task_id)))
+            }
+        }
+    }
+
+    async fn delete_task(&self, board_id: &str, id: &str, version: u64) -> CustomResult<Task> {
         let collection = self.get_tasks_collection();
+        let obj_id = ObjectId::from_str(id)?;
+        let query = version_filter(&obj_id, version);
+        match collection.find_one_and_delete(query, None).await? {
+            Some(task) => Ok(task),
+            None => {
+                let stored = self.read_task(board_id, id).await?;
+                check_version(stored.version, version, &format!("task {}", id))?;
+                Err(CustomError::InternalError(format!("task {} wasn't deleted", id)))
+            }
+        }
+    }
+
+    async fn delete_stage(
+        &self,
+        board_id: &str,
+        board: Board,
+        from: &str,
+        to: &str,
+    ) -> CustomResult<Board> {
+        if self.transactions {
+            self.delete_stage_in_transaction(board_id, board, from, to).await
+        } else {
+            self.delete_stage_in_order(board_id, board, from, to).await
+        }
+    }
+}
+
+impl Mongo {
+    async fn delete_stage_in_transaction(
+        &self,
+        board_id: &str,
+        mut board: Board,
+        from: &str,
+        to: &str,
+    ) -> CustomResult<Board> {
+        let board_obj_id = ObjectId::from_str(board_id)?;
+        let boards = self.get_boards_collection();
+        let tasks = self.get_tasks_collection();
+        let mut session = self.client.start_session(None).await?;
+        session.start_transaction(None).await?;
+
+        let query = version_filter(&board_obj_id, board.version);
+        board.id = None;
+        board.version += 1;
+        let update = doc! { "$set": ser::to_bson(&board)? };
+        let result = boards
+            .update_one_with_session(query, update, None, &mut session)
+            .await?;
+        if result.matched_count == 0 {
+            session.abort_transaction().await?;
+            let stored = self.read_board(board_id).await?;
+            check_version(stored.version, board.version - 1, &format!("board {}", board_id))?;
+            return Err(CustomError::InternalError(format!("board {} wasn't updated", board_id)));
+        }
+
+        let last_options = FindOneOptions::builder()
+            .sort(doc! { "rank": -1, "_id": -1 })
+            .build();
+        let query = doc! { "board_id": &board_obj_id, "stage": to };
+        let last = tasks
+            .find_one_with_session(query, last_options, &mut session)
+            .await?;
+        let moved_options = FindOptions::builder()
+            .sort(doc! { "rank": 1, "_id": 1 })
+            .build();
+        let query = doc! { "board_id": &board_obj_id, "stage": from };
+        let mut cursor = tasks
+            .find_with_session(query, moved_options, &mut session)
+            .await?;
+        let mut moved = Vec::new();
+        while let Some(task) = cursor.next(&mut session).await {
+            moved.extend(task?.id);
+        }
+
+        let ranks = rank::after(last.as_ref().map(|task| task.rank.as_str()), moved.len())?;
+        for (id, rank) in moved.iter().zip(ranks) {
+            let update = patch_update(doc! { "stage": to, "rank": rank });
+            tasks
+                .update_one_with_session(doc! { "_id": id }, update, None, &mut session)
+                .await?;
+        }
+        session.commit_transaction().await?;
+
+        board.id = Some(board_obj_id);
+        Ok(board)
+    }
+
+    /// Moves the tasks first, then stores the board if its version is still the same.
+    /// A board changed concurrently keeps the stage, which then has the tasks again.
+    async fn delete_stage_in_order(
+        &self,
+        board_id: &str,
+        board: Board,
+        from: &str,
+        to: &str,
+    ) -> CustomResult<Board> {
+        let stored = self.read_board(board_id).await?;
+        check_version(stored.version, board.version, &format!("board {}", board_id))?;
+
+        let board_obj_id = ObjectId::from_str(board_id)?;
+        let tasks = self.get_tasks_collection();
+        let last_options = FindOneOptions::builder()
+            .sort(doc! { "rank": -1, "_id": -1 })
+            .build();
+        let query = doc! { "board_id": &board_obj_id, "stage": to };
+        let last = tasks.find_one(query, last_options).await?;
+        let moved_options = FindOptions::builder()
+            .sort(doc! { "rank": 1, "_id": 1 })
+            .build();
+        let query = doc! { "board_id": &board_obj_id, "stage": from };
+        let mut cursor = tasks.find(query, moved_options).await?;
+        let mut moved = Vec::new();
+        while let Some(task) = cursor.next().await {
+            moved.extend(task?.id);
+        }
+
+        let ranks = rank::after(last.as_ref().map(|task| task.rank.as_str()), moved.len())?;
+        for (id, rank) in moved.iter().zip(ranks) {
+            let update = patch_update(doc! { "stage": to, "rank": rank });
+            tasks.update_one(doc! { "_id": id }, update, None).await?;
+        }
+        self.update_board(board_id, board).await
+    }
+}
+
+#[async_trait::async_trait]
+impl UsersDatabase for Mongo {
+    async fn create_user(&self, user: User) -> CustomResult<User> {
+        let collection = self.get_users_collection();
+        let insert_result = match collection.insert_one(&user, None).await {
+            Ok(insert_result) => insert_result,
+            Err(e) if is_duplicate_key(&e) => {
+                return Err(CustomError::Conflict(format!(
+                    "username is taken: {}",
+                    user.username
+                )))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let query = doc! { "_id": insert_result.inserted_id };
+        let user = collection.find_one(query, None).await?;
+        user.ok_or_else(|| CustomError::InternalError("created user is not found".into()))
+    }
+
+    async fn read_user(&self, id: &str) -> CustomResult<User> {
         let obj_id = ObjectId::from_str(id)?;
         let query = doc! { "_id": &obj_id };
-        let task = collection.find_one_and_delete(query, None).await?;
-        task.ok_or_else(|| CustomError::NotFound(format!("board with id: {}", id)))
+        let user = self.get_users_collection().find_one(query, None).await?;
+        user.ok_or_else(|| CustomError::NotFound(format!("user: {}", id)))
+    }
+
+    async fn read_user_by_name(&self, username: &str) -> CustomResult<User> {
+        let query = doc! { "username": username };
+        let user = self.get_users_collection().find_one(query, None).await?;
+        user.ok_or_else(|| CustomError::NotFound(format!("user: {}", username)))
     }
 }
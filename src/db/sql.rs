@@ -0,0 +1,749 @@
+use crate::db::{check_version, BoardsDatabase, EventMsgReceiver, TasksDatabase, UsersDatabase};
+use crate::errors::{CustomError, CustomResult};
+use crate::models::{
+    Board, BoardFilter, BoardPatch, ListQuery, Listable, Member, Page, SortField, SortOrder, Task,
+    TaskFilter, TaskPatch, User,
+};
+use crate::rank;
+use mongodb::bson::oid::ObjectId;
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::any::{AnyPool, AnyPoolOptions, AnyRow};
+use sqlx::{Any, Row, Transaction};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Schema migrations. Every entry is applied once, in order, inside a transaction.
+/// Never edit an already released migration: append a new one instead.
+const MIGRATIONS: &[&[&str]] = &[
+    // 1: boards and tasks
+    &[
+        "CREATE TABLE boards (
+            id TEXT PRIMARY KEY,
+            name TEXT NOT NULL,
+            description TEXT NOT NULL
+        )",
+        "CREATE TABLE tasks (
+            id TEXT PRIMARY KEY,
+            board_id TEXT NOT NULL REFERENCES boards (id) ON DELETE CASCADE,
+            name TEXT NOT NULL,
+            description TEXT NOT NULL,
+            stage TEXT NOT NULL
+        )",
+        "CREATE INDEX tasks_board_id ON tasks (board_id)",
+    ],
+    // 2: sorting by name
+    &[
+        "CREATE INDEX boards_name ON boards (name, id)",
+        "CREATE INDEX tasks_board_id_name ON tasks (board_id, name, id)",
+    ],
+    // 3: per-board stages, existing boards get the former fixed ones
+    &[r#"ALTER TABLE boards ADD COLUMN stages TEXT NOT NULL DEFAULT '[
+            {"id":"Backlog","name":"Backlog"},
+            {"id":"InProgress","name":"In progress"},
+            {"id":"Complete","name":"Complete"}
+        ]'"#],
+    // 4: manual order of tasks
+    &[
+        "ALTER TABLE tasks ADD COLUMN rank TEXT NOT NULL DEFAULT ''",
+        "CREATE INDEX tasks_board_id_rank ON tasks (board_id, rank, id)",
+    ],
+    // 5: users and authorship
+    &[
+        "CREATE TABLE users (
+            id TEXT PRIMARY KEY,
+            username TEXT NOT NULL UNIQUE,
+            password_hash TEXT NOT NULL
+        )",
+        "ALTER TABLE boards ADD COLUMN created_by TEXT",
+        "ALTER TABLE tasks ADD COLUMN created_by TEXT",
+    ],
+    // 6: board members, creators of existing boards become owners
+    &[
+        "CREATE TABLE board_members (
+            board_id TEXT NOT NULL REFERENCES boards (id) ON DELETE CASCADE,
+            user_id TEXT NOT NULL,
+            role TEXT NOT NULL,
+            PRIMARY KEY (board_id, user_id)
+        )",
+        "CREATE INDEX board_members_user_id ON board_members (user_id)",
+        "INSERT INTO board_members (board_id, user_id, role)
+        SELECT id, created_by, 'owner' FROM boards WHERE created_by IS NOT NULL",
+    ],
+    // 7: versions for optimistic concurrency
+    &[
+        "ALTER TABLE boards ADD COLUMN version BIGINT NOT NULL DEFAULT 0",
+        "ALTER TABLE tasks ADD COLUMN version BIGINT NOT NULL DEFAULT 0",
+    ],
+    // 8: ranks for tasks stored before manual order, which got an empty one: stages having
+    // such tasks are ranked anew in their current order, as `rank::nth` does
+    &["UPDATE tasks SET
+            rank = (
+                SELECT CAST(999999 + ranked.position AS TEXT) || 'i' FROM (
+                    SELECT id, ROW_NUMBER() OVER (PARTITION BY board_id, stage ORDER BY rank, id)
+                        AS position
+                    FROM tasks
+                ) AS ranked
+                WHERE ranked.id = tasks.id
+            ),
+            version = version + 1
+        WHERE EXISTS (
+            SELECT 1 FROM tasks AS legacy
+            WHERE legacy.board_id = tasks.board_id AND legacy.stage = tasks.stage
+                AND legacy.rank = ''
+        )"],
+];
+
+const SELECT_BOARDS: &str =
+    "SELECT id, name, description, stages, created_by, version FROM boards";
+const SELECT_TASKS: &str =
+    "SELECT id, board_id, name, description, stage, rank, created_by, version FROM tasks";
+const SELECT_USERS: &str = "SELECT id, username, password_hash FROM users";
+
+/// Error codes of unique constraint violation in PostgreSQL and SQLite.
+const UNIQUE_VIOLATION_CODES: &[&str] = &["23505", "2067"];
+
+/// Relational storage. Works with both PostgreSQL and SQLite, selected by connection string.
+#[derive(Debug, Clone)]
+pub struct Sql {
+    pool: AnyPool,
+}
+
+impl Sql {
+    pub async fn connect(connection_str: &str) -> CustomResult<Self> {
+        let pool = AnyPoolOptions::new().connect(connection_str).await?;
+        let sql = Self { pool };
+        sql.migrate().await?;
+        Ok(sql)
+    }
+
+    pub fn pool(&self) -> &AnyPool {
+        &self.pool
+    }
+
+    async fn migrate(&self) -> CustomResult<()> {
+        sqlx::query("CREATE TABLE IF NOT EXISTS schema_migrations (version BIGINT PRIMARY KEY)")
+            .execute(&self.pool)
+            .await?;
+
+        let applied: i64 = sqlx::query("SELECT COUNT(*) FROM schema_migrations")
+            .fetch_one(&self.pool)
+            .await?
+            .try_get(0)?;
+
+        for (version, statements) in MIGRATIONS.iter().enumerate().skip(applied as usize) {
+            let version = version as i64 + 1;
+            log::info!("Applying SQL migration #{}", version);
+
+            let mut transaction = self.pool.begin().await?;
+            for statement in statements.iter() {
+                sqlx::query(statement).execute(&mut transaction).await?;
+            }
+            sqlx::query("INSERT INTO schema_migrations (version) VALUES ($1)")
+                .bind(version)
+                .execute(&mut transaction)
+                .await?;
+            transaction.commit().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Selects one page of rows matching all `conditions`.
+    /// Conditions refer to `params` as `$1`, `$2` and so on.
+    async fn find_page<T>(
+        &self,
+        select: &str,
+        mut conditions: Vec<String>,
+        mut params: Vec<String>,
+        query: &ListQuery,
+        from_row: fn(&AnyRow) -> CustomResult<T>,
+    ) -> CustomResult<Page<T>>
+    where
+        T: Listable,
+    {
+        let mut param = |value: String| {
+            params.push(value);
+            format!("${}", params.len())
+        };
+
+        if let Some(prefix) = &query.name_prefix {
+            let prefix = param(prefix.clone());
+            conditions.push(format!("SUBSTR(name, 1, LENGTH({0})) = {0}", prefix));
+        }
+
+        let (direction, after) = match query.order {
+            SortOrder::Asc => ("ASC", ">"),
+            SortOrder::Desc => ("DESC", "<"),
+        };
+        let key_column = match query.sort() {
+            SortField::Id => None,
+            SortField::Name => Some("name"),
+            SortField::Rank => Some("rank"),
+        };
+
+        if let Some(cursor) = query.cursor()? {
+            let id = param(cursor.id.to_hex());
+            conditions.push(match (key_column, cursor.key) {
+                (Some(column), Some(key)) => {
+                    let key = param(key);
+                    format!(
+                        "({0} {1} {2} OR ({0} = {2} AND id {1} {3}))",
+                        column, after, key, id
+                    )
+                }
+                _ => format!("id {} {}", after, id),
+            });
+        }
+
+        let order_by = match key_column {
+            None => format!("id {}", direction),
+            Some(column) => format!("{0} {1}, id {1}", column, direction),
+        };
+        let where_clause = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+        let sql = format!(
+            "{} {} ORDER BY {} LIMIT {}",
+            select,
+            where_clause,
+            order_by,
+            query.limit() + 1
+        );
+
+        let mut select = sqlx::query(&sql);
+        for value in params.iter() {
+            select = select.bind(value.as_str());
+        }
+        let rows = select.fetch_all(&self.pool).await?;
+        let items = rows.iter().map(from_row).collect::<CustomResult<_>>()?;
+        Ok(Page::new(items, query))
+    }
+
+    /// Fills in members of boards read by `board_from_row`.
+    async fn load_members(&self, boards: &mut [Board]) -> CustomResult<()> {
+        if boards.is_empty() {
+            return Ok(());
+        }
+
+        let ids: Vec<String> = boards.iter().filter_map(|b| b.id).map(|id| id.to_hex()).collect();
+        let placeholders: Vec<String> = (1..=ids.len()).map(|i| format!("${}", i)).collect();
+        let sql = format!(
+            "SELECT board_id, user_id, role FROM board_members WHERE board_id IN ({})",
+            placeholders.join(", ")
+        );
+        let mut select = sqlx::query(&sql);
+        for id in ids.iter() {
+            select = select.bind(id.as_str());
+        }
+
+        let mut members: HashMap<String, Vec<Member>> = HashMap::new();
+        for row in select.fetch_all(&self.pool).await? {
+            let member = Member {
+                user_id: ObjectId::from_str(row.try_get("user_id")?)?,
+                role: row.try_get::<&str, _>("role")?.parse()?,
+            };
+            members.entry(row.try_get("board_id")?).or_default().push(member);
+        }
+
+        for board in boards.iter_mut() {
+            let id = board.id.map(|id| id.to_hex()).unwrap_or_default();
+            board.members = members.remove(&id).unwrap_or_default();
+        }
+        Ok(())
+    }
+
+    /// Replaces all members of the board.
+    async fn save_members(
+        transaction: &mut Transaction<'_, Any>,
+        board_id: &ObjectId,
+        members: &[Member],
+    ) -> CustomResult<()> {
+        sqlx::query("DELETE FROM board_members WHERE board_id = $1")
+            .bind(board_id.to_hex())
+            .execute(&mut *transaction)
+            .await?;
+        for member in members.iter() {
+            sqlx::query("INSERT INTO board_members (board_id, user_id, role) VALUES ($1, $2, $3)")
+                .bind(board_id.to_hex())
+                .bind(member.user_id.to_hex())
+                .bind(member.role.as_str())
+                .execute(&mut *transaction)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Stores board with its members and the next version, checking that `board.version` is stored.
+    async fn save_board(
+        transaction: &mut Transaction<'_, Any>,
+        id: &ObjectId,
+        board: &Board,
+    ) -> CustomResult<()> {
+        let result = sqlx::query(
+            "UPDATE boards SET name = $1, description = $2, stages = $3, version = version + 1
+            WHERE id = $4 AND version = $5",
+        )
+        .bind(&board.name)
+        .bind(&board.description)
+        .bind(serde_json::to_string(&board.stages)?)
+        .bind(id.to_hex())
+        .bind(board.version as i64)
+        .execute(&mut *transaction)
+        .await?;
+        if result.rows_affected() == 0 {
+            let row = sqlx::query("SELECT version FROM boards WHERE id = $1")
+                .bind(id.to_hex())
+                .fetch_optional(&mut *transaction)
+                .await?;
+            let row = row.ok_or_else(|| board_not_found(&id.to_hex()))?;
+            let stored: i64 = row.try_get("version")?;
+            check_version(stored as u64, board.version, &format!("board {}", id))?;
+        }
+        Self::save_members(transaction, id, &board.members).await
+    }
+
+    /// Sets only fields of the patch, named after columns, and increases version of the row
+    /// having `version`. Returns whether such a row was found.
+    async fn patch_row<P: Serialize>(
+        &self,
+        table: &str,
+        id: &ObjectId,
+        version: u64,
+        patch: &P,
+    ) -> CustomResult<bool> {
+        let fields = match serde_json::to_value(patch)? {
+            Value::Object(fields) => fields,
+            _ => return Err(CustomError::InternalError("patch must be an object".into())),
+        };
+        let mut assignments: Vec<String> = fields
+            .keys()
+            .enumerate()
+            .map(|(i, column)| format!("{} = ${}", column, i + 1))
+            .collect();
+        assignments.push("version = version + 1".into());
+        let sql = format!(
+            "UPDATE {} SET {} WHERE id = ${} AND version = ${}",
+            table,
+            assignments.join(", "),
+            fields.len() + 1,
+            fields.len() + 2
+        );
+
+        let mut update = sqlx::query(&sql);
+        for (column, value) in fields.iter() {
+            update = match value {
+                Value::String(value) => update.bind(value.as_str()),
+                Value::Bool(value) => update.bind(*value),
+                Value::Number(value) => match value.as_i64() {
+                    Some(value) => update.bind(value),
+                    None => update.bind(value.as_f64()),
+                },
+                Value::Null => update.bind(None::<&str>),
+                Value::Array(_) | Value::Object(_) => {
+                    return Err(CustomError::InternalError(format!(
+                        "can't patch column {} with a nested value",
+                        column
+                    )))
+                }
+            };
+        }
+        let result = update
+            .bind(id.to_hex())
+            .bind(version as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    fn board_from_row(row: &AnyRow) -> CustomResult<Board> {
+        Ok(Board {
+            id: Some(ObjectId::from_str(row.try_get("id")?)?),
+            name: row.try_get("name")?,
+            description: row.try_get("description")?,
+            stages: serde_json::from_str(row.try_get("stages")?)?,
+            created_by: optional_id(row, "created_by")?,
+            members: Vec::new(),
+            version: row.try_get::<i64, _>("version")? as u64,
+        })
+    }
+
+    fn task_from_row(row: &AnyRow) -> CustomResult<Task> {
+        Ok(Task {
+            id: Some(ObjectId::from_str(row.try_get("id")?)?),
+            board_id: Some(ObjectId::from_str(row.try_get("board_id")?)?),
+            name: row.try_get("name")?,
+            description: row.try_get("description")?,
+            stage: row.try_get("stage")?,
+            rank: row.try_get("rank")?,
+            created_by: optional_id(row, "created_by")?,
+            version: row.try_get::<i64, _>("version")? as u64,
+        })
+    }
+
+    fn user_from_row(row: &AnyRow) -> CustomResult<User> {
+        Ok(User {
+            id: Some(ObjectId::from_str(row.try_get("id")?)?),
+            username: row.try_get("username")?,
+            password_hash: row.try_get("password_hash")?,
+        })
+    }
+}
+
+fn optional_id(row: &AnyRow, column: &str) -> CustomResult<Option<ObjectId>> {
+    let id: Option<&str> = row.try_get(column)?;
+    Ok(id.map(ObjectId::from_str).transpose()?)
+}
+
+fn is_unique_violation(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::Database(e) => e
+            .code()
+            .is_some_and(|code| UNIQUE_VIOLATION_CODES.contains(&code.as_ref())),
+        _ => false,
+    }
+}
+
+fn board_not_found(id: &str) -> CustomError {
+    CustomError::NotFound(format!("board with id: {}", id))
+}
+
+fn task_not_found(id: &str) -> CustomError {
+    CustomError::NotFound(format!("task with id: {}", id))
+}
+
+#[async_trait::async_trait]
+impl BoardsDatabase for Sql {
+    async fn create_board(&self, mut board: Board) -> CustomResult<Board> {
+        let id = ObjectId::new();
+        let mut transaction = self.pool.begin().await?;
+        sqlx::query(
+            "INSERT INTO boards (id, name, description, stages, created_by, version)
+            VALUES ($1, $2, $3, $4, $5, 1)",
+        )
+        .bind(id.to_hex())
+        .bind(&board.name)
+        .bind(&board.description)
+        .bind(serde_json::to_string(&board.stages)?)
+        .bind(board.created_by.map(|id| id.to_hex()))
+        .execute(&mut transaction)
+        .await?;
+        Self::save_members(&mut transaction, &id, &board.members).await?;
+        transaction.commit().await?;
+        board.id = Some(id);
+        board.version = 1;
+        Ok(board)
+    }
+
+    async fn read_boards(
+        &self,
+        query: &ListQuery,
+        filter: &BoardFilter,
+    ) -> CustomResult<Page<Board>> {
+        let mut conditions = vec![];
+        let mut params = vec![];
+        if let Some(user_id) = &filter.member {
+            params.push(user_id.to_hex());
+            conditions.push(format!(
+                "id IN (SELECT board_id FROM board_members WHERE user_id = ${})",
+                params.len()
+            ));
+        }
+        let mut page = self
+            .find_page(SELECT_BOARDS, conditions, params, query, Self::board_from_row)
+            .await?;
+        self.load_members(&mut page.items).await?;
+        Ok(page)
+    }
+
+    async fn read_board(&self, id: &str) -> CustomResult<Board> {
+        let obj_id = ObjectId::from_str(id)?;
+        let row = sqlx::query(&format!("{} WHERE id = $1", SELECT_BOARDS))
+            .bind(obj_id.to_hex())
+            .fetch_optional(&self.pool)
+            .await?;
+        let row = row.ok_or_else(|| board_not_found(id))?;
+        let mut board = Self::board_from_row(&row)?;
+        self.load_members(std::slice::from_mut(&mut board)).await?;
+        Ok(board)
+    }
+
+    async fn update_board(&self, id: &str, mut board: Board) -> CustomResult<Board> {
+        let obj_id = ObjectId::from_str(id)?;
+        let mut transaction = self.pool.begin().await?;
+        Self::save_board(&mut transaction, &obj_id, &board).await?;
+        transaction.commit().await?;
+        board.id = Some(obj_id);
+        board.version += 1;
+        Ok(board)
+    }
+
+    async fn patch_board(&self, id: &str, version: u64, patch: &BoardPatch) -> CustomResult<Board> {
+        let obj_id = ObjectId::from_str(id)?;
+        let patched = self.patch_row("boards", &obj_id, version, patch).await?;
+        let board = self.read_board(id).await?;
+        if !patched {
+            check_version(board.version, version, &format!("board {}", id))?;
+        }
+        Ok(board)
+    }
+
+    async fn delete_board(&self, id: &str, version: u64) -> CustomResult<Board> {
+        let obj_id = ObjectId::from_str(id)?;
+        let mut transaction = self.pool.begin().await?;
+        let row = sqlx::query(&format!("{} WHERE id = $1", SELECT_BOARDS))
+            .bind(obj_id.to_hex())
+            .fetch_optional(&mut transaction)
+            .await?;
+        let mut board = Self::board_from_row(&row.ok_or_else(|| board_not_found(id))?)?;
+        check_version(board.version, version, &format!("board {}", id))?;
+        self.load_members(std::slice::from_mut(&mut board)).await?;
+
+        // Board's tasks and members are deleted by the foreign key cascade.
+        let result = sqlx::query("DELETE FROM boards WHERE id = $1 AND version = $2")
+            .bind(obj_id.to_hex())
+            .bind(version as i64)
+            .execute(&mut transaction)
+            .await?;
+        if result.rows_affected() == 0 {
+            let stored = self.read_board(id).await?;
+            check_version(stored.version, version, &format!("board {}", id))?;
+            return Err(CustomError::InternalError(format!("board {} wasn't deleted", id)));
+        }
+        transaction.commit().await?;
+        Ok(board)
+    }
+
+    async fn subscribe_on_board_updates(
+        &self,
+        _board_id: &str,
+        _last_event_id: Option<u64>,
+    ) -> CustomResult<EventMsgReceiver> {
+        Err(CustomError::InternalError(
+            "Subscription isn't implemented for sql".into(),
+        ))
+    }
+}
+
+#[async_trait::async_trait]
+impl TasksDatabase for Sql {
+    async fn create_task(&self, board_id: &str, mut task: Task) -> CustomResult<Task> {
+        let board_obj_id = ObjectId::from_str(board_id)?;
+        let id = ObjectId::new();
+        let mut transaction = self.pool.begin().await?;
+        let board = sqlx::query("SELECT id FROM boards WHERE id = $1")
+            .bind(board_obj_id.to_hex())
+            .fetch_optional(&mut transaction)
+            .await?;
+        board.ok_or_else(|| board_not_found(board_id))?;
+
+        sqlx::query(
+            "INSERT INTO tasks (id, board_id, name, description, stage, rank, created_by, version)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, 1)",
+        )
+        .bind(id.to_hex())
+        .bind(board_obj_id.to_hex())
+        .bind(&task.name)
+        .bind(&task.description)
+        .bind(&task.stage)
+        .bind(&task.rank)
+        .bind(task.created_by.map(|id| id.to_hex()))
+        .execute(&mut transaction)
+        .await?;
+        transaction.commit().await?;
+
+        task.id = Some(id);
+        task.board_id = Some(board_obj_id);
+        task.version = 1;
+        Ok(task)
+    }
+
+    async fn read_tasks(
+        &self,
+        board_id: &str,
+        query: &ListQuery,
+        filter: &TaskFilter,
+    ) -> CustomResult<Page<Task>> {
+        let board_obj_id = ObjectId::from_str(board_id)?;
+        let mut conditions = vec!["board_id = $1".to_string()];
+        let mut params = vec![board_obj_id.to_hex()];
+        if let Some(stage) = &filter.stage {
+            params.push(stage.clone());
+            conditions.push(format!("stage = ${}", params.len()));
+        }
+        self.find_page(SELECT_TASKS, conditions, params, query, Self::task_from_row)
+            .await
+    }
+
+    async fn read_task(&self, _: &str, task_id: &str) -> CustomResult<Task> {
+        let obj_id = ObjectId::from_str(task_id)?;
+        let row = sqlx::query(&format!("{} WHERE id = $1", SELECT_TASKS))
+            .bind(obj_id.to_hex())
+            .fetch_optional(&self.pool)
+            .await?;
+        let row = row.ok_or_else(|| task_not_found(task_id))?;
+        Self::task_from_row(&row)
+    }
+
+    async fn update_task(&self, board_id: &str, task_id: &str, mut task: Task) -> CustomResult<Task> {
+        let board_obj_id = ObjectId::from_str(board_id)?;
+        let task_obj_id = ObjectId::from_str(task_id)?;
+        let result = sqlx::query(
+            "UPDATE tasks SET name = $1, description = $2, stage = $3, rank = $4,
+            version = version + 1
+            WHERE id = $5 AND board_id = $6 AND version = $7",
+        )
+        .bind(&task.name)
+        .bind(&task.description)
+        .bind(&task.stage)
+        .bind(&task.rank)
+        .bind(task_obj_id.to_hex())
+        .bind(board_obj_id.to_hex())
+        .bind(task.version as i64)
+        .execute(&self.pool)
+        .await?;
+        if result.rows_affected() == 0 {
+            let stored = self.read_task(board_id, task_id).await?;
+            if stored.board_id != Some(board_obj_id) {
+                return Err(task_not_found(task_id));
+            }
+            check_version(stored.version, task.version, &format!("task {}", task_id))?;
+        }
+        task.id = Some(task_obj_id);
+        task.board_id = Some(board_obj_id);
+        task.version += 1;
+        Ok(task)
+    }
+
+    async fn patch_task(
+        &self,
+        board_id: &str,
+        task_id: &str,
+        version: u64,
+        patch: &TaskPatch,
+    ) -> CustomResult<Task> {
+        let board_obj_id = ObjectId::from_str(board_id)?;
+        let task_obj_id = ObjectId::from_str(task_id)?;
+        let stored = self.read_task(board_id, task_id).await?;
+        if stored.board_id != Some(board_obj_id) {
+            return Err(task_not_found(task_id));
+        }
+        let patched = self.patch_row("tasks", &task_obj_id, version, patch).await?;
+        let task = self.read_task(board_id, task_id).await?;
+        if !patched {
+            check_version(task.version, version, &format!("task {}", task_id))?;
+        }
+        Ok(task)
+    }
+
+    async fn delete_task(&self, board_id: &str, task_id: &str, version: u64) -> CustomResult<Task> {
+        let obj_id = ObjectId::from_str(task_id)?;
+        let mut transaction = self.pool.begin().await?;
+        let row = sqlx::query(&format!("{} WHERE id = $1", SELECT_TASKS))
+            .bind(obj_id.to_hex())
+            .fetch_optional(&mut transaction)
+            .await?;
+        let task = Self::task_from_row(&row.ok_or_else(|| task_not_found(task_id))?)?;
+        check_version(task.version, version, &format!("task {}", task_id))?;
+
+        let result = sqlx::query("DELETE FROM tasks WHERE id = $1 AND version = $2")
+            .bind(obj_id.to_hex())
+            .bind(version as i64)
+            .execute(&mut transaction)
+            .await?;
+        if result.rows_affected() == 0 {
+            let stored = self.read_task(board_id, task_id).await?;
+            check_version(stored.version, version, &format!("task {}", task_id))?;
+            return Err(CustomError::InternalError(format!("task {} wasn't deleted", task_id)));
+        }
+        transaction.commit().await?;
+        Ok(task)
+    }
+
+    async fn delete_stage(
+        &self,
+        board_id: &str,
+        mut board: Board,
+        from: &str,
+        to: &str,
+    ) -> CustomResult<Board> {
+        let board_obj_id = ObjectId::from_str(board_id)?;
+        let mut transaction = self.pool.begin().await?;
+        Self::save_board(&mut transaction, &board_obj_id, &board).await?;
+
+        let last = sqlx::query(
+            "SELECT rank FROM tasks WHERE board_id = $1 AND stage = $2
+            ORDER BY rank DESC, id DESC LIMIT 1",
+        )
+        .bind(board_obj_id.to_hex())
+        .bind(to)
+        .fetch_optional(&mut transaction)
+        .await?;
+        let last: Option<String> = last.map(|row| row.try_get("rank")).transpose()?;
+        let moved = sqlx::query("SELECT id FROM tasks WHERE board_id = $1 AND stage = $2 ORDER BY rank, id")
+            .bind(board_obj_id.to_hex())
+            .bind(from)
+            .fetch_all(&mut transaction)
+            .await?;
+        let ranks = rank::after(last.as_deref(), moved.len())?;
+        for (row, rank) in moved.iter().zip(ranks) {
+            sqlx::query("UPDATE tasks SET stage = $1, rank = $2, version = version + 1 WHERE id = $3")
+                .bind(to)
+                .bind(rank)
+                .bind(row.try_get::<&str, _>("id")?)
+                .execute(&mut transaction)
+                .await?;
+        }
+        transaction.commit().await?;
+
+        board.id = Some(board_obj_id);
+        board.version += 1;
+        Ok(board)
+    }
+}
+
+#[async_trait::async_trait]
+impl UsersDatabase for Sql {
+    async fn create_user(&self, mut user: User) -> CustomResult<User> {
+        let id = ObjectId::new();
+        let result = sqlx::query("INSERT INTO users (id, username, password_hash) VALUES ($1, $2, $3)")
+            .bind(id.to_hex())
+            .bind(&user.username)
+            .bind(&user.password_hash)
+            .execute(&self.pool)
+            .await;
+        match result {
+            Ok(_) => {}
+            Err(e) if is_unique_violation(&e) => {
+                return Err(CustomError::Conflict(format!(
+                    "username is taken: {}",
+                    user.username
+                )))
+            }
+            Err(e) => return Err(e.into()),
+        }
+        user.id = Some(id);
+        Ok(user)
+    }
+
+    async fn read_user(&self, id: &str) -> CustomResult<User> {
+        let obj_id = ObjectId::from_str(id)?;
+        let row = sqlx::query(&format!("{} WHERE id = $1", SELECT_USERS))
+            .bind(obj_id.to_hex())
+            .fetch_optional(&self.pool)
+            .await?;
+        let row = row.ok_or_else(|| CustomError::NotFound(format!("user: {}", id)))?;
+        Self::user_from_row(&row)
+    }
+
+    async fn read_user_by_name(&self, username: &str) -> CustomResult<User> {
+        let row = sqlx::query(&format!("{} WHERE username = $1", SELECT_USERS))
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .await?;
+        let row = row.ok_or_else(|| CustomError::NotFound(format!("user: {}", username)))?;
+        Self::user_from_row(&row)
+    }
+}
@@ -1,5 +1,9 @@
+use crate::metrics;
+use crate::rate_lim;
+use crate::request_id;
+use crate::validation::FieldError;
 use actix_web::body::Body;
-use actix_web::http::header::ContentType;
+use actix_web::http::header;
 use actix_web::http::StatusCode;
 use actix_web::{HttpResponse, ResponseError};
 use redis::RedisError;
@@ -12,40 +16,227 @@ pub enum CustomError {
     MongoDbError(String),
     #[error("Redis error: {0}")]
     RedisError(String),
+    #[error("SQL error: {0}")]
+    SqlError(String),
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+    #[error("Invalid id: {0}")]
+    InvalidId(String),
+    #[error("Invalid fields: {}", describe_fields(.0))]
+    Validation(Vec<FieldError>),
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    #[error("Precondition failed: {0}")]
+    PreconditionFailed(String),
+    #[error("Unsupported media type: {0}")]
+    UnsupportedMediaType(String),
     #[error("Endpoint is not found: {0}")]
     NotFound(String),
     #[error("Internal error: {0}")]
     InternalError(String),
-    #[error("Too many requests: {actual} requests when {max} allowed")]
-    TooManyRequests { actual: u64, max: u64 },
+    #[error("Service unavailable: {0}")]
+    ServiceUnavailable(String),
+    #[error("Too many requests: {max} allowed per {window} seconds")]
+    TooManyRequests {
+        max: u64,
+        window: u64,
+        /// Seconds until the next request is allowed.
+        retry_after: u64,
+    },
+    #[error("Too many streams: {max} open at once allowed")]
+    TooManyStreams { max: u64 },
 }
 
 pub type CustomResult<T> = Result<T, CustomError>;
 
+const PROBLEM_JSON: &str = "application/problem+json";
+
 impl ResponseError for CustomError {
     fn status_code(&self) -> StatusCode {
         match self {
             Self::MongoDbError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::RedisError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::SqlError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::InvalidId(_) => StatusCode::BAD_REQUEST,
+            Self::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            Self::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
             Self::NotFound(_) => StatusCode::NOT_FOUND,
             Self::InternalError(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            Self::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::TooManyRequests { .. } | Self::TooManyStreams { .. } => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
         }
     }
 
     fn error_response(&self) -> HttpResponse<Body> {
-        log::error!("Error: {}", self.to_string());
+        let request_id = request_id::current();
+        let status = self.status_code().as_u16();
+        metrics::error(self.variant());
+        // Internal details stay in the log, found by the request id sent to the client.
+        if self.status_code().is_server_error() {
+            log::error!(code = self.code(), status = status; "Request failed: {}", self);
+        } else {
+            log::debug!(code = self.code(), status = status; "Request failed: {}", self);
+        }
+
+        let problem = Problem {
+            problem_type: format!("/problems/{}", self.code().replace('_', "-")),
+            title: self.title(),
+            status: self.status_code().as_u16(),
+            detail: self.detail(),
+            code: self.code(),
+            request_id,
+            errors: self.field_errors(),
+        };
+
+        let mut response = HttpResponse::build(self.status_code());
+        let limit = match self {
+            Self::TooManyRequests {
+                max, retry_after, ..
+            } => Some((*max, *retry_after)),
+            Self::TooManyStreams { max } => Some((*max, rate_lim::STREAM_RETRY_AFTER)),
+            _ => None,
+        };
+        if let Some((max, retry_after)) = limit {
+            for header in rate_lim::limit_headers(max, 0, retry_after) {
+                response.insert_header(header);
+            }
+            response.insert_header((header::RETRY_AFTER, retry_after));
+        }
+
+        response.content_type(PROBLEM_JSON).json(problem)
+    }
+}
+
+impl CustomError {
+    /// Stable machine-readable error code.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::MongoDbError(_)
+            | Self::RedisError(_)
+            | Self::SqlError(_)
+            | Self::InternalError(_) => "internal_error",
+            Self::BadRequest(_) => "bad_request",
+            Self::InvalidId(_) => "invalid_id",
+            Self::Validation(_) => "validation_failed",
+            Self::Unauthorized(_) => "unauthorized",
+            Self::Forbidden(_) => "forbidden",
+            Self::Conflict(_) => "conflict",
+            Self::PreconditionFailed(_) => "precondition_failed",
+            Self::UnsupportedMediaType(_) => "unsupported_media_type",
+            Self::NotFound(_) => "not_found",
+            Self::ServiceUnavailable(_) => "service_unavailable",
+            Self::TooManyRequests { .. } => "too_many_requests",
+            Self::TooManyStreams { .. } => "too_many_streams",
+        }
+    }
+
+    /// Name of the variant, telling apart storage errors hidden behind one code.
+    pub fn variant(&self) -> &'static str {
+        match self {
+            Self::MongoDbError(_) => "MongoDbError",
+            Self::RedisError(_) => "RedisError",
+            Self::SqlError(_) => "SqlError",
+            Self::BadRequest(_) => "BadRequest",
+            Self::InvalidId(_) => "InvalidId",
+            Self::Validation(_) => "Validation",
+            Self::Unauthorized(_) => "Unauthorized",
+            Self::Forbidden(_) => "Forbidden",
+            Self::Conflict(_) => "Conflict",
+            Self::PreconditionFailed(_) => "PreconditionFailed",
+            Self::UnsupportedMediaType(_) => "UnsupportedMediaType",
+            Self::NotFound(_) => "NotFound",
+            Self::InternalError(_) => "InternalError",
+            Self::ServiceUnavailable(_) => "ServiceUnavailable",
+            Self::TooManyRequests { .. } => "TooManyRequests",
+            Self::TooManyStreams { .. } => "TooManyStreams",
+        }
+    }
+
+    /// Short summary, the same for every error with the code.
+    pub fn title(&self) -> &'static str {
+        match self {
+            Self::MongoDbError(_)
+            | Self::RedisError(_)
+            | Self::SqlError(_)
+            | Self::InternalError(_) => "Internal server error",
+            Self::BadRequest(_) => "Bad request",
+            Self::InvalidId(_) => "Invalid id",
+            Self::Validation(_) => "Invalid fields",
+            Self::Unauthorized(_) => "Unauthorized",
+            Self::Forbidden(_) => "Forbidden",
+            Self::Conflict(_) => "Conflict",
+            Self::PreconditionFailed(_) => "Precondition failed",
+            Self::UnsupportedMediaType(_) => "Unsupported media type",
+            Self::NotFound(_) => "Not found",
+            Self::ServiceUnavailable(_) => "Service unavailable",
+            Self::TooManyRequests { .. } => "Too many requests",
+            Self::TooManyStreams { .. } => "Too many streams",
+        }
+    }
 
-        let error_response = ErrorResponse(self.to_string());
+    /// Explanation safe to show to a client: messages of storage errors are hidden.
+    pub fn detail(&self) -> String {
+        match self {
+            Self::MongoDbError(_)
+            | Self::RedisError(_)
+            | Self::SqlError(_)
+            | Self::InternalError(_) => "request failed because of a server error".into(),
+            Self::BadRequest(message)
+            | Self::InvalidId(message)
+            | Self::Unauthorized(message)
+            | Self::Forbidden(message)
+            | Self::Conflict(message)
+            | Self::PreconditionFailed(message)
+            | Self::UnsupportedMediaType(message)
+            | Self::NotFound(message)
+            | Self::ServiceUnavailable(message) => message.clone(),
+            Self::Validation(errors) => describe_fields(errors),
+            Self::TooManyRequests { .. } | Self::TooManyStreams { .. } => self.to_string(),
+        }
+    }
 
-        HttpResponse::build(self.status_code())
-            .content_type(ContentType::json())
-            .json(error_response)
+    /// Problems with request fields, empty unless it's a `Validation` error.
+    pub fn field_errors(&self) -> &[FieldError] {
+        match self {
+            Self::Validation(errors) => errors,
+            _ => &[],
+        }
     }
 }
 
+fn describe_fields(errors: &[FieldError]) -> String {
+    let described: Vec<String> = errors
+        .iter()
+        .map(|error| format!("{} {}", error.field, error.message))
+        .collect();
+    described.join(", ")
+}
+
+/// RFC 7807 problem details.
 #[derive(Serialize)]
-struct ErrorResponse(String);
+struct Problem<'a> {
+    #[serde(rename = "type")]
+    problem_type: String,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    code: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    errors: &'a [FieldError],
+}
 
 impl From<mongodb::error::Error> for CustomError {
     fn from(source: mongodb::error::Error) -> Self {
@@ -67,7 +258,7 @@ impl From<mongodb::bson::ser::Error> for CustomError {
 
 impl From<mongodb::bson::oid::Error> for CustomError {
     fn from(source: mongodb::bson::oid::Error) -> Self {
-        Self::NotFound(source.to_string())
+        Self::InvalidId(source.to_string())
     }
 }
 
@@ -77,6 +268,18 @@ impl From<RedisError> for CustomError {
     }
 }
 
+impl From<sqlx::Error> for CustomError {
+    fn from(source: sqlx::Error) -> Self {
+        Self::SqlError(source.to_string())
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for CustomError {
+    fn from(source: jsonwebtoken::errors::Error) -> Self {
+        Self::Unauthorized(source.to_string())
+    }
+}
+
 impl From<serde_json::Error> for CustomError {
     fn from(source: serde_json::Error) -> Self {
         Self::InternalError(source.to_string())
@@ -0,0 +1,186 @@
+use crate::errors::CustomResult;
+use crate::models::{Board, Task};
+use actix_web::web::Bytes;
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Change of a board or its tasks, delivered to board subscribers.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BoardEvent {
+    TaskCreated {
+        board_id: ObjectId,
+        task: Task,
+    },
+    TaskUpdated {
+        board_id: ObjectId,
+        task_id: ObjectId,
+        task: Task,
+        changes: BTreeMap<String, Change>,
+    },
+    TaskDeleted {
+        board_id: ObjectId,
+        task_id: ObjectId,
+    },
+    /// All tasks of a stage were moved to another one.
+    TasksMoved {
+        board_id: ObjectId,
+        from_stage: String,
+        to_stage: String,
+    },
+    BoardUpdated {
+        board_id: ObjectId,
+        board: Board,
+        #[serde(default)]
+        changes: BTreeMap<String, Change>,
+    },
+    BoardDeleted {
+        board_id: ObjectId,
+    },
+    /// Events the client missed are no longer kept, so it should reload the board.
+    Resync {
+        board_id: ObjectId,
+    },
+}
+
+/// Board event with its position in the board's event log.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredEvent {
+    /// Starts from 1 and increases by one with every event of the board.
+    pub id: u64,
+    /// Id of the request which caused the event.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    pub event: BoardEvent,
+}
+
+/// SSE data: the event with the id of the request which caused it.
+#[derive(Serialize)]
+struct EventData<'a> {
+    #[serde(flatten)]
+    event: &'a BoardEvent,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a str>,
+}
+
+/// Old and new values of a changed field.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Change {
+    pub old: Value,
+    pub new: Value,
+}
+
+impl BoardEvent {
+    pub fn task_updated(
+        board_id: ObjectId,
+        task_id: ObjectId,
+        old: &Task,
+        new: Task,
+    ) -> CustomResult<Self> {
+        Ok(Self::TaskUpdated {
+            board_id,
+            task_id,
+            changes: diff(old, &new)?,
+            task: new,
+        })
+    }
+
+    pub fn board_updated(board_id: ObjectId, old: &Board, new: Board) -> CustomResult<Self> {
+        Ok(Self::BoardUpdated {
+            board_id,
+            changes: diff(old, &new)?,
+            board: new,
+        })
+    }
+
+    /// Event type, also used as SSE event name.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::TaskCreated { .. } => "task_created",
+            Self::TaskUpdated { .. } => "task_updated",
+            Self::TaskDeleted { .. } => "task_deleted",
+            Self::TasksMoved { .. } => "tasks_moved",
+            Self::BoardUpdated { .. } => "board_updated",
+            Self::BoardDeleted { .. } => "board_deleted",
+            Self::Resync { .. } => "resync",
+        }
+    }
+
+    /// Whether the user has no access to the board after the event, e.g. being removed from members.
+    pub fn revokes_access(&self, user_id: &ObjectId) -> bool {
+        matches!(self, Self::BoardUpdated { board, .. } if board.role_of(user_id).is_none())
+    }
+
+    pub fn board_id(&self) -> &ObjectId {
+        match self {
+            Self::TaskCreated { board_id, .. }
+            | Self::TaskUpdated { board_id, .. }
+            | Self::TaskDeleted { board_id, .. }
+            | Self::TasksMoved { board_id, .. }
+            | Self::BoardUpdated { board_id, .. }
+            | Self::BoardDeleted { board_id }
+            | Self::Resync { board_id } => board_id,
+        }
+    }
+}
+
+impl StoredEvent {
+    /// Formats event as a Server-Sent Events frame. Its id comes back in `Last-Event-ID` on reconnect.
+    pub fn to_sse(&self) -> CustomResult<Bytes> {
+        let data = serde_json::to_string(&EventData {
+            event: &self.event,
+            request_id: self.request_id.as_deref(),
+        })?;
+        Ok(Bytes::from(format!(
+            "id: {}\nevent: {}\ndata: {}\n\n",
+            self.id,
+            self.event.name(),
+            data
+        )))
+    }
+}
+
+/// Selects events of the board's log which a client that has seen events up to `last_id` missed.
+/// If some of them are already dropped from the log, `Resync` event goes first.
+pub fn replay(log: Vec<StoredEvent>, board_id: ObjectId, last_id: u64) -> Vec<StoredEvent> {
+    let first_id = log.first().map_or(1, |event| event.id);
+    let latest_id = log.last().map_or(0, |event| event.id);
+    let mut missed: Vec<StoredEvent> = log.into_iter().filter(|event| event.id > last_id).collect();
+
+    // Either events were trimmed or ids were issued by another log, e.g. before restart.
+    if last_id + 1 < first_id || last_id > latest_id {
+        let resync = StoredEvent {
+            id: missed.first().map_or(latest_id, |event| event.id - 1),
+            request_id: None,
+            event: BoardEvent::Resync { board_id },
+        };
+        missed.insert(0, resync);
+    }
+    missed
+}
+
+/// Returns top-level fields which differ between two serialized values.
+fn diff<T: Serialize>(old: &T, new: &T) -> CustomResult<BTreeMap<String, Change>> {
+    let (old, new) = match (serde_json::to_value(old)?, serde_json::to_value(new)?) {
+        (Value::Object(old), Value::Object(new)) => (old, new),
+        _ => return Ok(BTreeMap::new()),
+    };
+
+    let mut changes = BTreeMap::new();
+    for key in old.keys().chain(new.keys()) {
+        let old_value = old.get(key).cloned().unwrap_or(Value::Null);
+        let new_value = new.get(key).cloned().unwrap_or(Value::Null);
+        if old_value != new_value {
+            changes.insert(
+                key.clone(),
+                Change {
+                    old: old_value,
+                    new: new_value,
+                },
+            );
+        }
+    }
+    Ok(changes)
+}
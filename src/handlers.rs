@@ -1,123 +1,728 @@
+use crate::auth::{AuthUser, StreamUser};
 use crate::boards::Boards;
+use crate::db::EventMsgResult;
 use crate::errors::{CustomError, CustomResult};
-use crate::models::{Board, Task};
+use crate::health::{Health, Liveness};
+use crate::members::Members;
+use crate::metrics;
+use crate::models::{
+    Board, Credentials, DeleteStageQuery, ListQuery, MemberRole, MoveTask, NewMember, Patch,
+    Precondition, RefreshToken, SocketQuery, SortField, StageName, Task, TaskFilter,
+};
+use crate::socket::BoardSocket;
+use crate::stages::Stages;
 use crate::tasks::Tasks;
-use actix_web::http::{header, StatusCode};
-use actix_web::{web, HttpResponse};
+use crate::users::Users;
+use actix_web::http::header::{self, ETag, EntityTag, Header, IfMatch, IfNoneMatch};
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use serde::Serialize;
 use std::sync::Arc;
+use tokio_stream::StreamExt;
+
+#[actix_web::post("/auth/register")]
+#[tracing::instrument(skip_all)]
+pub async fn register(
+    credentials: web::Json<Credentials>,
+    users: web::Data<Arc<Users>>,
+) -> CustomResult<HttpResponse> {
+    let user = users.register(credentials.into_inner()).await?;
+    Ok(HttpResponse::Created().json(user))
+}
+
+#[actix_web::post("/auth/login")]
+#[tracing::instrument(skip_all)]
+pub async fn login(
+    credentials: web::Json<Credentials>,
+    users: web::Data<Arc<Users>>,
+) -> CustomResult<HttpResponse> {
+    let tokens = users.login(credentials.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(tokens))
+}
+
+#[actix_web::post("/auth/refresh")]
+#[tracing::instrument(skip_all)]
+pub async fn refresh(
+    token: web::Json<RefreshToken>,
+    users: web::Data<Arc<Users>>,
+) -> CustomResult<HttpResponse> {
+    let tokens = users.refresh(&token.refresh_token).await?;
+    Ok(HttpResponse::Ok().json(tokens))
+}
+
+#[actix_web::get("/auth/me")]
+#[tracing::instrument(skip_all)]
+pub async fn me(user: AuthUser, users: web::Data<Arc<Users>>) -> CustomResult<HttpResponse> {
+    let user = users.read_user(&user.id).await?;
+    Ok(HttpResponse::Ok().json(user))
+}
 
 #[actix_web::get("/boards")]
-pub async fn read_boards(boards: web::Data<Arc<Boards>>) -> CustomResult<HttpResponse> {
-    let boards = boards.read_boards().await?;
+#[tracing::instrument(skip_all)]
+pub async fn read_boards(
+    user: AuthUser,
+    query: web::Query<ListQuery>,
+    boards: web::Data<Arc<Boards>>,
+) -> CustomResult<HttpResponse> {
+    let boards = boards.read_boards(&query, &user.id).await?;
     Ok(HttpResponse::Ok().json(boards))
 }
 
 #[actix_web::post("/boards")]
+#[tracing::instrument(skip_all)]
 pub async fn create_board(
+    user: AuthUser,
     board_data: web::Json<Board>,
     boards: web::Data<Arc<Boards>>,
 ) -> CustomResult<HttpResponse> {
     let board_data = board_data.into_inner();
-    let board = boards.create_board(board_data).await?;
-    Ok(HttpResponse::Ok().json(board))
+    let board = boards.create_board(board_data, &user.id).await?;
+    Ok(HttpResponse::Ok().insert_header(etag(board.version)).json(board))
 }
 
 #[actix_web::get("/boards/{board_id}")]
+#[tracing::instrument(skip_all)]
 pub async fn read_board(
+    user: AuthUser,
+    req: HttpRequest,
     board_id: web::Path<String>,
     boards: web::Data<Arc<Boards>>,
 ) -> CustomResult<HttpResponse> {
     let id = board_id.into_inner();
-    let board = boards.read_board(&id).await?;
-    Ok(HttpResponse::Ok().json(board))
+    let board = boards.read_board(&id, &user.id).await?;
+    Ok(conditional(&req, board.version, &board))
 }
 
 #[actix_web::put("/boards/{board_id}")]
+#[tracing::instrument(skip_all)]
 pub async fn update_board(
+    user: AuthUser,
+    req: HttpRequest,
     board_id: web::Path<String>,
     board: web::Json<Board>,
     boards: web::Data<Arc<Boards>>,
 ) -> CustomResult<HttpResponse> {
     let id = board_id.into_inner();
     let board = board.into_inner();
-    let board = boards.update_board(&id, board).await?;
-    Ok(HttpResponse::Ok().json(board))
+    let board = boards.update_board(&id, board, &if_match(&req)?, &user.id).await?;
+    Ok(HttpResponse::Ok().insert_header(etag(board.version)).json(board))
+}
+
+#[actix_web::patch("/boards/{board_id}")]
+#[tracing::instrument(skip_all)]
+pub async fn patch_board(
+    user: AuthUser,
+    req: HttpRequest,
+    board_id: web::Path<String>,
+    body: Bytes,
+    boards: web::Data<Arc<Boards>>,
+) -> CustomResult<HttpResponse> {
+    let id = board_id.into_inner();
+    let patch = patch(&req, &body)?;
+    let board = boards.patch_board(&id, &patch, &if_match(&req)?, &user.id).await?;
+    Ok(HttpResponse::Ok().insert_header(etag(board.version)).json(board))
 }
 
 #[actix_web::delete("/boards/{board_id}")]
+#[tracing::instrument(skip_all)]
 pub async fn delete_board(
+    user: AuthUser,
+    req: HttpRequest,
     board_id: web::Path<String>,
     boards: web::Data<Arc<Boards>>,
 ) -> CustomResult<HttpResponse> {
     let id = board_id.into_inner();
-    let board = boards.delete_board(&id).await?;
+    let board = boards.delete_board(&id, &if_match(&req)?, &user.id).await?;
+    Ok(HttpResponse::Ok().json(board))
+}
+
+#[actix_web::post("/boards/{board_id}/stages")]
+#[tracing::instrument(skip_all)]
+pub async fn create_stage(
+    user: AuthUser,
+    board_id: web::Path<String>,
+    stage: web::Json<StageName>,
+    stages: web::Data<Arc<Stages>>,
+) -> CustomResult<HttpResponse> {
+    let board_id = board_id.into_inner();
+    let name = stage.into_inner().name;
+    let board = stages.add_stage(&board_id, name, &user.id).await?;
+    Ok(HttpResponse::Ok().json(board))
+}
+
+#[actix_web::put("/boards/{board_id}/stages")]
+#[tracing::instrument(skip_all)]
+pub async fn reorder_stages(
+    user: AuthUser,
+    board_id: web::Path<String>,
+    order: web::Json<Vec<String>>,
+    stages: web::Data<Arc<Stages>>,
+) -> CustomResult<HttpResponse> {
+    let board_id = board_id.into_inner();
+    let order = order.into_inner();
+    let board = stages.reorder_stages(&board_id, order, &user.id).await?;
+    Ok(HttpResponse::Ok().json(board))
+}
+
+#[actix_web::put("/boards/{board_id}/stages/{stage_id}")]
+#[tracing::instrument(skip_all)]
+pub async fn rename_stage(
+    user: AuthUser,
+    ids: web::Path<(String, String)>,
+    stage: web::Json<StageName>,
+    stages: web::Data<Arc<Stages>>,
+) -> CustomResult<HttpResponse> {
+    let (board_id, stage_id) = ids.into_inner();
+    let name = stage.into_inner().name;
+    let board = stages.rename_stage(&board_id, &stage_id, name, &user.id).await?;
     Ok(HttpResponse::Ok().json(board))
 }
 
+#[actix_web::delete("/boards/{board_id}/stages/{stage_id}")]
+#[tracing::instrument(skip_all)]
+pub async fn delete_stage(
+    user: AuthUser,
+    ids: web::Path<(String, String)>,
+    query: web::Query<DeleteStageQuery>,
+    stages: web::Data<Arc<Stages>>,
+) -> CustomResult<HttpResponse> {
+    let (board_id, stage_id) = ids.into_inner();
+    let move_to = query.move_to.as_deref();
+    let board = stages.delete_stage(&board_id, &stage_id, move_to, &user.id).await?;
+    Ok(HttpResponse::Ok().json(board))
+}
+
+#[actix_web::get("/boards/{board_id}/members")]
+#[tracing::instrument(skip_all)]
+pub async fn read_members(
+    user: AuthUser,
+    board_id: web::Path<String>,
+    members: web::Data<Arc<Members>>,
+) -> CustomResult<HttpResponse> {
+    let board_id = board_id.into_inner();
+    let members = members.read_members(&board_id, &user.id).await?;
+    Ok(HttpResponse::Ok().json(members))
+}
+
+#[actix_web::post("/boards/{board_id}/members")]
+#[tracing::instrument(skip_all)]
+pub async fn invite_member(
+    user: AuthUser,
+    board_id: web::Path<String>,
+    invitation: web::Json<NewMember>,
+    members: web::Data<Arc<Members>>,
+) -> CustomResult<HttpResponse> {
+    let board_id = board_id.into_inner();
+    let invitation = invitation.into_inner();
+    let member = members.invite(&board_id, invitation, &user.id).await?;
+    Ok(HttpResponse::Ok().json(member))
+}
+
+#[actix_web::put("/boards/{board_id}/members/{user_id}")]
+#[tracing::instrument(skip_all)]
+pub async fn change_member_role(
+    user: AuthUser,
+    ids: web::Path<(String, String)>,
+    role: web::Json<MemberRole>,
+    members: web::Data<Arc<Members>>,
+) -> CustomResult<HttpResponse> {
+    let (board_id, member_id) = ids.into_inner();
+    let role = role.into_inner().role;
+    let member = members
+        .change_role(&board_id, &member_id, role, &user.id)
+        .await?;
+    Ok(HttpResponse::Ok().json(member))
+}
+
+#[actix_web::delete("/boards/{board_id}/members/{user_id}")]
+#[tracing::instrument(skip_all)]
+pub async fn remove_member(
+    user: AuthUser,
+    ids: web::Path<(String, String)>,
+    members: web::Data<Arc<Members>>,
+) -> CustomResult<HttpResponse> {
+    let (board_id, member_id) = ids.into_inner();
+    members.remove(&board_id, &member_id, &user.id).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
 #[actix_web::get("/boards/{board_id}/tasks")]
+#[tracing::instrument(skip_all)]
 pub async fn read_tasks(
+    user: AuthUser,
     board_id: web::Path<String>,
+    query: web::Query<ListQuery>,
+    filter: web::Query<TaskFilter>,
     tasks: web::Data<Arc<Tasks>>,
 ) -> CustomResult<HttpResponse> {
     let board_id = board_id.into_inner();
-    let tasks = tasks.read_board_tasks(&board_id).await?;
+    let mut query = query.into_inner();
+    query.sort.get_or_insert(SortField::Rank);
+    let tasks = tasks.read_board_tasks(&board_id, &query, &filter, &user.id).await?;
     Ok(HttpResponse::Ok().json(tasks))
 }
 
 #[actix_web::post("/boards/{board_id}/tasks")]
+#[tracing::instrument(skip_all)]
 pub async fn create_task(
+    user: AuthUser,
     board_id: web::Path<String>,
     task: web::Json<Task>,
     tasks: web::Data<Arc<Tasks>>,
 ) -> CustomResult<HttpResponse> {
     let task = task.into_inner();
     let board_id = board_id.into_inner();
-    let task = tasks.create_task(&board_id, task).await?;
-    Ok(HttpResponse::Ok().json(task))
+    let task = tasks.create_task(&board_id, task, &user.id).await?;
+    Ok(HttpResponse::Ok().insert_header(etag(task.version)).json(task))
 }
 
 #[actix_web::get("/boards/{board_id}/tasks/{task_id}")]
+#[tracing::instrument(skip_all)]
 pub async fn read_task(
+    user: AuthUser,
+    req: HttpRequest,
     ids: web::Path<(String, String)>,
     tasks: web::Data<Arc<Tasks>>,
 ) -> CustomResult<HttpResponse> {
     let (board_id, task_id) = ids.into_inner();
-    let tasks = tasks.read_task(&board_id, &task_id).await?;
-    Ok(HttpResponse::Ok().json(tasks))
+    let task = tasks.read_task(&board_id, &task_id, &user.id).await?;
+    Ok(conditional(&req, task.version, &task))
 }
 
 #[actix_web::put("/boards/{board_id}/tasks/{task_id}")]
+#[tracing::instrument(skip_all)]
 pub async fn update_task(
+    user: AuthUser,
+    req: HttpRequest,
     ids: web::Path<(String, String)>,
     task: web::Json<Task>,
     tasks: web::Data<Arc<Tasks>>,
 ) -> Result<HttpResponse, CustomError> {
     let (board_id, task_id) = ids.into_inner();
     let task = task.into_inner();
-    let task = tasks.update_task(&board_id, &task_id, task).await?;
-    Ok(HttpResponse::Ok().json(task))
+    let if_match = if_match(&req)?;
+    let task = tasks.update_task(&board_id, &task_id, task, &if_match, &user.id).await?;
+    Ok(HttpResponse::Ok().insert_header(etag(task.version)).json(task))
+}
+
+#[actix_web::patch("/boards/{board_id}/tasks/{task_id}")]
+#[tracing::instrument(skip_all)]
+pub async fn patch_task(
+    user: AuthUser,
+    req: HttpRequest,
+    ids: web::Path<(String, String)>,
+    body: Bytes,
+    tasks: web::Data<Arc<Tasks>>,
+) -> CustomResult<HttpResponse> {
+    let (board_id, task_id) = ids.into_inner();
+    let patch = patch(&req, &body)?;
+    let if_match = if_match(&req)?;
+    let task = tasks.patch_task(&board_id, &task_id, &patch, &if_match, &user.id).await?;
+    Ok(HttpResponse::Ok().insert_header(etag(task.version)).json(task))
+}
+
+#[actix_web::post("/boards/{board_id}/tasks/{task_id}/move")]
+#[tracing::instrument(skip_all)]
+pub async fn move_task(
+    user: AuthUser,
+    req: HttpRequest,
+    ids: web::Path<(String, String)>,
+    target: web::Json<MoveTask>,
+    tasks: web::Data<Arc<Tasks>>,
+) -> CustomResult<HttpResponse> {
+    let (board_id, task_id) = ids.into_inner();
+    let target = target.into_inner();
+    let if_match = if_match(&req)?;
+    let task = tasks.move_task(&board_id, &task_id, target, &if_match, &user.id).await?;
+    Ok(HttpResponse::Ok().insert_header(etag(task.version)).json(task))
 }
 
 #[actix_web::delete("/boards/{board_id}/tasks/{task_id}")]
+#[tracing::instrument(skip_all)]
 pub async fn delete_task(
+    user: AuthUser,
+    req: HttpRequest,
     ids: web::Path<(String, String)>,
     tasks: web::Data<Arc<Tasks>>,
 ) -> Result<HttpResponse, CustomError> {
     let (board_id, task_id) = ids.into_inner();
-    let task = tasks.delete_task(&board_id, &task_id).await?;
+    let if_match = if_match(&req)?;
+    let task = tasks.delete_task(&board_id, &task_id, &if_match, &user.id).await?;
     Ok(HttpResponse::Ok().json(task))
 }
 
 #[actix_web::get("/boards/{board_id}/updates")]
+#[tracing::instrument(skip_all)]
 pub async fn subscribe_board_changes(
+    user: StreamUser,
+    req: HttpRequest,
     board_id: web::Path<String>,
     boards: web::Data<Arc<Boards>>,
 ) -> Result<HttpResponse, CustomError> {
     let board_id = board_id.into_inner();
-    let updates_stream = boards.subscribe_on_board_updates(&board_id).await?;
-    let response_stream = tokio_stream::wrappers::ReceiverStream::new(updates_stream);
+    // Sent by EventSource on reconnect: events after it are replayed.
+    let last_event_id = match req.headers().get("Last-Event-ID") {
+        Some(value) => Some(
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .ok_or_else(|| CustomError::BadRequest("invalid Last-Event-ID".into()))?,
+        ),
+        None => None,
+    };
+    let updates_stream = boards
+        .subscribe_on_board_updates(&board_id, last_event_id, &user.id)
+        .await?;
+    // Counted as open until the response stream is dropped.
+    let subscriber = metrics::Subscriber::new("sse");
+    // The stream ends once the user is removed from the board's members.
+    let member = move |event: &EventMsgResult| {
+        !matches!(event, Ok(event) if event.event.revokes_access(&user.id))
+    };
+    let events = tokio_stream::wrappers::ReceiverStream::new(updates_stream)
+        .take_while(member)
+        .map(move |event| {
+            let _subscriber = &subscriber;
+            event.and_then(|event| event.to_sse())
+        });
+    // Comment frame tells the client that subscription is established.
+    let connected = tokio_stream::once(Ok(Bytes::from_static(b": connected\n\n")));
+    let response_stream = connected.chain(events);
 
     Ok(HttpResponse::build(StatusCode::OK)
         .insert_header(header::ContentType(mime::TEXT_EVENT_STREAM))
         .streaming(response_stream))
 }
+
+#[actix_web::get("/boards/{board_id}/ws")]
+#[tracing::instrument(skip_all)]
+pub async fn board_socket(
+    user: StreamUser,
+    req: HttpRequest,
+    stream: web::Payload,
+    board_id: web::Path<String>,
+    query: web::Query<SocketQuery>,
+    boards: web::Data<Arc<Boards>>,
+    tasks: web::Data<Arc<Tasks>>,
+) -> CustomResult<HttpResponse> {
+    let board_id = board_id.into_inner();
+    let events = boards
+        .subscribe_on_board_updates(&board_id, query.last_event_id, &user.id)
+        .await?;
+    let socket = BoardSocket::new(board_id, user.id, Arc::clone(&tasks), events);
+    ws::start(socket, &req, stream).map_err(|e| CustomError::BadRequest(e.to_string()))
+}
+
+/// Liveness probe, answered as long as the process handles requests.
+#[actix_web::get("/healthz")]
+#[tracing::instrument(skip_all)]
+pub async fn liveness() -> CustomResult<HttpResponse> {
+    Ok(HttpResponse::Ok().json(Liveness { status: "ok" }))
+}
+
+/// Readiness probe pinging every dependency. Answers `503` if any of them is down.
+#[actix_web::get("/readyz")]
+#[tracing::instrument(skip_all)]
+pub async fn readiness(health: web::Data<Arc<Health>>) -> CustomResult<HttpResponse> {
+    let readiness = health.check().await;
+    let status = if readiness.is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(HttpResponse::build(status).json(readiness))
+}
+
+/// Metrics in Prometheus text format.
+#[actix_web::get("/metrics")]
+#[tracing::instrument(skip_all)]
+pub async fn read_metrics() -> CustomResult<HttpResponse> {
+    let rendered = metrics::render().map_err(|e| CustomError::InternalError(e.to_string()))?;
+    Ok(HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(rendered))
+}
+
+/// Reports malformed JSON body, query or path as `BadRequest`, like the rest of invalid input.
+pub fn input_error<E: std::fmt::Display>(error: E, _: &HttpRequest) -> actix_web::Error {
+    CustomError::BadRequest(error.to_string()).into()
+}
+
+fn etag(version: u64) -> ETag {
+    ETag(EntityTag::strong(version.to_string()))
+}
+
+/// Reads `If-Match`. Weak and malformed tags never match: the comparison must be strong.
+fn if_match(req: &HttpRequest) -> CustomResult<Precondition> {
+    if !req.headers().contains_key(header::IF_MATCH) {
+        return Ok(Precondition(None));
+    }
+    let header = IfMatch::parse(req)
+        .map_err(|_| CustomError::BadRequest("invalid If-Match header".into()))?;
+    let versions = match header {
+        IfMatch::Any => return Ok(Precondition(None)),
+        IfMatch::Items(tags) => tags
+            .iter()
+            .filter(|tag| !tag.weak)
+            .filter_map(|tag| tag.tag().parse().ok())
+            .collect(),
+    };
+    Ok(Precondition(Some(versions)))
+}
+
+/// Parses `PATCH` body as JSON Patch or JSON Merge Patch, depending on `Content-Type`.
+/// Plain JSON is taken for a merge patch.
+fn patch(req: &HttpRequest, body: &[u8]) -> CustomResult<Patch> {
+    let content_type = req.headers().get(header::CONTENT_TYPE);
+    let content_type = content_type.and_then(|value| value.to_str().ok()).unwrap_or_default();
+    let mime_type = content_type.split(';').next().unwrap_or_default().trim();
+    let invalid = |e: serde_json::Error| CustomError::BadRequest(format!("invalid patch: {}", e));
+    match mime_type.to_ascii_lowercase().as_str() {
+        "application/json-patch+json" => {
+            Ok(Patch::Json(serde_json::from_slice(body).map_err(invalid)?))
+        }
+        "application/merge-patch+json" | "application/json" => {
+            Ok(Patch::Merge(serde_json::from_slice(body).map_err(invalid)?))
+        }
+        _ => Err(CustomError::UnsupportedMediaType(format!(
+            "patch must be application/json-patch+json or application/merge-patch+json, got: {}",
+            content_type
+        ))),
+    }
+}
+
+/// Responds with the entity and its `ETag`, or with 304 if `If-None-Match` has the current one.
+fn conditional<T: Serialize>(req: &HttpRequest, version: u64, entity: &T) -> HttpResponse {
+    let current = etag(version);
+    let not_modified = match IfNoneMatch::parse(req) {
+        Ok(IfNoneMatch::Any) => true,
+        Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&current)),
+        Err(_) => false,
+    };
+    if not_modified {
+        HttpResponse::NotModified().insert_header(current).finish()
+    } else {
+        HttpResponse::Ok().insert_header(current).json(entity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Auth;
+    use crate::config::AuthConfig;
+    use crate::db::memory::Memory;
+    use crate::models::Board;
+    use crate::Services;
+    use actix_web::{test, App};
+    use mongodb::bson::oid::ObjectId;
+    use serde_json::{json, Value};
+
+    fn services() -> (Services, Arc<Auth>) {
+        let auth = Arc::new(Auth::new(&AuthConfig {
+            jwt_secret: "secret of the tests, 32 bytes long".into(),
+            access_token_ttl: 900,
+            refresh_token_ttl: 3600,
+        }));
+        (Services::new(Memory::new(16), &auth), auth)
+    }
+
+    fn routes(services: &Services, auth: &Arc<Auth>) -> impl FnOnce(&mut web::ServiceConfig) {
+        let boards = Arc::clone(&services.boards);
+        let users = Arc::clone(&services.users);
+        let auth = Arc::clone(auth);
+        move |config| {
+            config
+                .service(register)
+                .service(login)
+                .service(create_board)
+                .service(read_board)
+                .service(patch_board)
+                .app_data(web::Data::new(boards))
+                .app_data(web::Data::new(users))
+                .app_data(web::Data::new(auth))
+                .app_data(web::JsonConfig::default().error_handler(input_error));
+        }
+    }
+
+    /// Creates a board owned by a new user. Returns the board id and the user's authorization.
+    async fn owned_board(services: &Services, auth: &Auth) -> (String, String) {
+        let user_id = ObjectId::new();
+        let board = json!({"name": "Release", "description": "Tasks of the release"});
+        let board: Board = serde_json::from_value(board).unwrap();
+        let board = services.boards.create_board(board, &user_id).await.unwrap();
+        let tokens = auth.issue_tokens(&user_id).unwrap();
+        (board.id.unwrap().to_hex(), format!("Bearer {}", tokens.access_token))
+    }
+
+    fn patch_request(id: &str, bearer: &str, content_type: &str, body: Value) -> test::TestRequest {
+        test::TestRequest::patch()
+            .uri(&format!("/boards/{}", id))
+            .insert_header((header::AUTHORIZATION, bearer))
+            .insert_header((header::CONTENT_TYPE, content_type))
+            .set_payload(body.to_string())
+    }
+
+    #[actix_rt::test]
+    async fn creates_and_reads_board() {
+        let (services, auth) = services();
+        let app = test::init_service(App::new().configure(routes(&services, &auth))).await;
+
+        let credentials = json!({"username": "alice", "password": "correct horse"});
+        let req = test::TestRequest::post()
+            .uri("/auth/register")
+            .set_json(&credentials)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+        let req = test::TestRequest::post()
+            .uri("/auth/login")
+            .set_json(&credentials)
+            .to_request();
+        let tokens: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        let bearer = format!("Bearer {}", tokens["access_token"].as_str().unwrap());
+
+        let req = test::TestRequest::post()
+            .uri("/boards")
+            .insert_header((header::AUTHORIZATION, bearer.as_str()))
+            .set_json(&json!({"name": "Release", "description": "Tasks of the release"}))
+            .to_request();
+        let created: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        let id = created["_id"]["$oid"].as_str().unwrap();
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/boards/{}", id))
+            .insert_header((header::AUTHORIZATION, bearer.as_str()))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(header::ETAG).unwrap(), "\"1\"");
+        let board: Value = test::read_body_json(res).await;
+        assert_eq!(board["name"], "Release");
+        assert_eq!(board["members"][0]["role"], "owner");
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/boards/{}", id))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn merge_patch_clears_field_with_null() {
+        let (services, auth) = services();
+        let (id, bearer) = owned_board(&services, &auth).await;
+        let app = test::init_service(App::new().configure(routes(&services, &auth))).await;
+
+        let patch = json!({"name": "Renamed", "description": null});
+        let req = patch_request(&id, &bearer, "application/merge-patch+json", patch).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(header::ETAG).unwrap(), "\"2\"");
+        let board: Value = test::read_body_json(res).await;
+        assert_eq!(board["name"], "Renamed");
+        assert_eq!(board["description"], "");
+        assert_eq!(board["version"], 2);
+    }
+
+    #[actix_rt::test]
+    async fn json_patch_with_failed_test_conflicts() {
+        let (services, auth) = services();
+        let (id, bearer) = owned_board(&services, &auth).await;
+        let app = test::init_service(App::new().configure(routes(&services, &auth))).await;
+
+        let patch = json!([
+            {"op": "test", "path": "/name", "value": "Old"},
+            {"op": "replace", "path": "/name", "value": "New"},
+        ]);
+        let req = patch_request(&id, &bearer, "application/json-patch+json", patch).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let problem: Value = test::read_body_json(res).await;
+        assert_eq!(problem["code"], "conflict");
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/boards/{}", id))
+            .insert_header((header::AUTHORIZATION, bearer.as_str()))
+            .to_request();
+        let board: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        assert_eq!(board["name"], "Release");
+        assert_eq!(board["version"], 1);
+    }
+
+    #[actix_rt::test]
+    async fn patch_keeps_board_without_editable_changes() {
+        let (services, auth) = services();
+        let (id, bearer) = owned_board(&services, &auth).await;
+        let app = test::init_service(App::new().configure(routes(&services, &auth))).await;
+
+        // Nothing changes, so nothing is stored.
+        let patch = json!({"name": "Release"});
+        let req = patch_request(&id, &bearer, "application/json", patch).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(header::ETAG).unwrap(), "\"1\"");
+
+        // Fields of the server or other endpoints are rejected.
+        let patch = json!({"version": 7, "stages": []});
+        let req = patch_request(&id, &bearer, "application/merge-patch+json", patch).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let problem: Value = test::read_body_json(res).await;
+        assert_eq!(problem["errors"].as_array().unwrap().len(), 2);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/boards/{}", id))
+            .insert_header((header::AUTHORIZATION, bearer.as_str()))
+            .to_request();
+        let board: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        assert_eq!(board["version"], 1);
+        assert_eq!(board["stages"].as_array().unwrap().len(), 3);
+    }
+
+    #[actix_rt::test]
+    async fn rejects_invalid_board() {
+        let (services, auth) = services();
+        let (_, bearer) = owned_board(&services, &auth).await;
+        let app = test::init_service(App::new().configure(routes(&services, &auth))).await;
+
+        let name = "n".repeat(201);
+        let req = test::TestRequest::post()
+            .uri("/boards")
+            .insert_header((header::AUTHORIZATION, bearer.as_str()))
+            .set_json(&json!({"name": name, "description": ""}))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let content_type = res.headers().get(header::CONTENT_TYPE).unwrap();
+        assert_eq!(content_type, "application/problem+json");
+        let problem: Value = test::read_body_json(res).await;
+        assert_eq!(problem["code"], "validation_failed");
+        assert_eq!(
+            problem["errors"],
+            json!([{"field": "name", "message": "must be at most 200 characters long"}])
+        );
+
+        let req = test::TestRequest::post()
+            .uri("/boards")
+            .insert_header((header::AUTHORIZATION, bearer.as_str()))
+            .set_json(&json!({"name": " ", "description": ""}))
+            .to_request();
+        let problem: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        assert_eq!(problem["errors"][0]["message"], "must not be empty");
+
+        // Malformed body is a bad request rather than an invalid board.
+        let req = test::TestRequest::post()
+            .uri("/boards")
+            .insert_header((header::AUTHORIZATION, bearer.as_str()))
+            .insert_header((header::CONTENT_TYPE, "application/json"))
+            .set_payload("{\"name\": ")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let problem: Value = test::read_body_json(res).await;
+        assert_eq!(problem["code"], "bad_request");
+    }
+}
@@ -0,0 +1,187 @@
+use crate::errors::{CustomError, CustomResult};
+use actix_web::rt::time::{sleep, timeout};
+use mongodb::bson::doc;
+use serde::Serialize;
+use sqlx::AnyPool;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+/// Time a dependency has to answer a readiness ping.
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+/// First pause between attempts to connect on start, doubled after every failure.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// External service the application can't work without.
+#[derive(Clone)]
+pub enum Dependency {
+    Mongo(mongodb::Client),
+    Sql(AnyPool),
+    Redis(redis::Client),
+}
+
+impl Dependency {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Mongo(_) => "mongo",
+            Self::Sql(_) => "sql",
+            Self::Redis(_) => "redis",
+        }
+    }
+
+    pub async fn ping(&self) -> CustomResult<()> {
+        match self {
+            Self::Mongo(client) => {
+                client
+                    .database("admin")
+                    .run_command(doc! {"ping": 1}, None)
+                    .await?;
+            }
+            Self::Sql(pool) => {
+                sqlx::query("SELECT 1").execute(pool).await?;
+            }
+            Self::Redis(client) => {
+                let mut connection = client.get_async_connection().await?;
+                redis::cmd("PING")
+                    .query_async::<_, String>(&mut connection)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Answer of the liveness probe: the process handles requests.
+#[derive(Serialize, Debug)]
+pub struct Liveness {
+    pub status: &'static str,
+}
+
+/// Result of checking one dependency.
+#[derive(Serialize, Debug)]
+pub struct Check {
+    /// `up` or `down`.
+    pub status: &'static str,
+    pub latency_ms: u64,
+    /// Why the dependency is down. Details are only logged.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'static str>,
+}
+
+/// Readiness of the application, with status of every dependency.
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+    /// `ready` if every dependency is up, `not_ready` otherwise.
+    pub status: &'static str,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.checks.values().all(|check| check.status == "up")
+    }
+}
+
+/// Checks dependencies the application was started with.
+pub struct Health {
+    dependencies: Vec<Dependency>,
+}
+
+impl Health {
+    pub fn new(dependencies: Vec<Dependency>) -> Self {
+        Self { dependencies }
+    }
+
+    /// Pings all dependencies at once, each one within `PING_TIMEOUT`.
+    pub async fn check(&self) -> Readiness {
+        let checks = self.dependencies.iter().map(|dependency| async move {
+            let started = Instant::now();
+            let error = match timeout(PING_TIMEOUT, dependency.ping()).await {
+                Ok(Ok(())) => None,
+                Ok(Err(e)) => {
+                    log::warn!("Readiness check of {} failed: {}", dependency.name(), e);
+                    Some("unreachable")
+                }
+                Err(_) => {
+                    log::warn!("Readiness check of {} timed out", dependency.name());
+                    Some("timed out")
+                }
+            };
+            let check = Check {
+                status: if error.is_none() { "up" } else { "down" },
+                latency_ms: started.elapsed().as_millis() as u64,
+                error,
+            };
+            (dependency.name(), check)
+        });
+
+        let checks: BTreeMap<_, _> = futures::future::join_all(checks).await.into_iter().collect();
+        let mut readiness = Readiness {
+            status: "ready",
+            checks,
+        };
+        if !readiness.is_ready() {
+            readiness.status = "not_ready";
+        }
+        readiness
+    }
+}
+
+/// Connects to dependencies on start: once, or retrying with backoff until the deadline.
+pub struct Startup {
+    deadline: Option<Instant>,
+}
+
+impl Startup {
+    /// Fails on the first error.
+    pub fn immediate() -> Self {
+        Self { deadline: None }
+    }
+
+    /// Retries for `timeout` in total.
+    pub fn waiting(timeout: Duration) -> Self {
+        Self {
+            deadline: Some(Instant::now() + timeout),
+        }
+    }
+
+    pub async fn connect<T, F, Fut>(&self, name: &str, mut connect: F) -> CustomResult<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = CustomResult<T>>,
+    {
+        let deadline = match self.deadline {
+            Some(deadline) => deadline,
+            None => return connect().await,
+        };
+
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let error = match timeout(remaining, connect()).await {
+                Ok(Ok(connected)) => return Ok(connected),
+                Ok(Err(e)) => e,
+                Err(_) => CustomError::ServiceUnavailable(format!("{} didn't answer", name)),
+            };
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                log::error!("Giving up connecting to {} after {} attempts", name, attempt);
+                return Err(error);
+            }
+            let pause = backoff.min(remaining);
+            log::warn!(
+                "Can't connect to {} (attempt {}), retrying in {:?}: {}",
+                name,
+                attempt,
+                pause,
+                error
+            );
+            sleep(pause).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+            attempt += 1;
+        }
+    }
+}
@@ -0,0 +1,392 @@
+use crate::request_id;
+use chrono::{DateTime, Local};
+use log::kv::{self, Key, Value, VisitSource};
+use serde_json::{Map, Value as Json};
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::{env, fmt};
+
+/// Format of log records.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    /// `[2021-10-01T12:00:00.000+02:00][target][INFO][request id] message key=value`
+    Text,
+    /// One JSON object per line with `timestamp`, `level`, `target`, `message`,
+    /// `request_id` and the record's key/value fields.
+    Json,
+}
+
+/// How often the log file is started anew, besides reaching its size limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Interval {
+    Hourly,
+    Daily,
+    Never,
+}
+
+impl Interval {
+    /// Records made within one period go to the same file.
+    fn period(self, time: &DateTime<Local>) -> String {
+        match self {
+            Self::Hourly => time.format("%Y%m%d%H").to_string(),
+            Self::Daily => time.format("%Y%m%d").to_string(),
+            Self::Never => String::new(),
+        }
+    }
+}
+
+/// Sets up logging from environment variables:
+/// - `LOG_LEVEL`: default level followed by per-module overrides, like `info,boards_back::db=debug`;
+/// - `LOG_FORMAT`: `text` or `json`;
+/// - `LOG_FILE`: file the log is also written to, appended across restarts;
+/// - `LOG_ROTATE_SIZE`: size in bytes after which the file is rotated;
+/// - `LOG_ROTATE_INTERVAL`: `hourly`, `daily` or `never`;
+/// - `LOG_RETAIN`: number of rotated files kept.
+pub fn init() -> Result<(), Box<dyn Error>> {
+    let levels = env::var("LOG_LEVEL").unwrap_or_else(|_| "info".into());
+    let format = match env::var("LOG_FORMAT").as_deref() {
+        Err(_) | Ok("text") => Format::Text,
+        Ok("json") => Format::Json,
+        Ok(other) => return Err(format!("unknown LOG_FORMAT: {}", other).into()),
+    };
+
+    let mut builder = fern::Dispatch::new().format(move |out, message, record| {
+        let line = match format {
+            Format::Text => text_record(message, record),
+            Format::Json => json_record(message, record),
+        };
+        out.finish(format_args!("{}", line))
+    });
+    builder = with_levels(builder, &levels)?.chain(io::stderr());
+
+    if let Ok(path) = env::var("LOG_FILE") {
+        let max_size = match env::var("LOG_ROTATE_SIZE") {
+            Ok(size) => Some(
+                size.parse()
+                    .map_err(|_| "LOG_ROTATE_SIZE must be a number of bytes")?,
+            ),
+            Err(_) => None,
+        };
+        let interval = match env::var("LOG_ROTATE_INTERVAL").as_deref() {
+            Err(_) | Ok("never") => Interval::Never,
+            Ok("hourly") => Interval::Hourly,
+            Ok("daily") => Interval::Daily,
+            Ok(other) => return Err(format!("unknown LOG_ROTATE_INTERVAL: {}", other).into()),
+        };
+        let retain = match env::var("LOG_RETAIN") {
+            Ok(retain) => retain
+                .parse()
+                .map_err(|_| "LOG_RETAIN must be a number of files")?,
+            Err(_) => DEFAULT_RETAIN,
+        };
+        let file = RotatingFile::open(path.into(), max_size, interval, retain)?;
+        builder = builder.chain(Box::new(file) as Box<dyn Write + Send>);
+    }
+
+    builder.apply()?;
+
+    log::trace!("TRACE output enabled");
+    log::debug!("DEBUG output enabled");
+    log::info!("INFO output enabled");
+    log::warn!("WARN output enabled");
+    log::error!("ERROR output enabled");
+
+    Ok(())
+}
+
+/// Rotated files kept if `LOG_RETAIN` isn't set.
+const DEFAULT_RETAIN: usize = 7;
+
+/// Applies `LOG_LEVEL`: comma separated default level and `module=level` overrides.
+fn with_levels(
+    mut builder: fern::Dispatch,
+    levels: &str,
+) -> Result<fern::Dispatch, Box<dyn Error>> {
+    let parse = |level: &str| {
+        level
+            .trim()
+            .parse::<log::LevelFilter>()
+            .map_err(|_| format!("unknown log level in LOG_LEVEL: {}", level))
+    };
+
+    builder = builder.level(log::LevelFilter::Info);
+    for directive in levels
+        .split(',')
+        .filter(|directive| !directive.trim().is_empty())
+    {
+        builder = match directive.split_once('=') {
+            Some((module, level)) => builder.level_for(module.trim().to_string(), parse(level)?),
+            None => builder.level(parse(directive)?),
+        };
+    }
+    Ok(builder)
+}
+
+fn timestamp() -> String {
+    Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z").to_string()
+}
+
+fn text_record(message: &fmt::Arguments, record: &log::Record) -> String {
+    let mut line = format!("[{}][{}][{}]", timestamp(), record.target(), record.level());
+    // Records made while handling a request are marked with its id.
+    if let Some(id) = request_id::current() {
+        let _ = write!(line, "[{}]", id);
+    }
+    let _ = write!(line, " {}", message);
+
+    let mut fields = Fields::default();
+    let _ = record.key_values().visit(&mut fields);
+    for (key, value) in fields.0 {
+        match value {
+            Json::String(value) => {
+                let _ = write!(line, " {}={:?}", key, value);
+            }
+            value => {
+                let _ = write!(line, " {}={}", key, value);
+            }
+        }
+    }
+    line
+}
+
+fn json_record(message: &fmt::Arguments, record: &log::Record) -> String {
+    let mut object = Map::new();
+    object.insert("timestamp".into(), timestamp().into());
+    object.insert("level".into(), record.level().as_str().into());
+    object.insert("target".into(), record.target().into());
+    object.insert("message".into(), message.to_string().into());
+    if let Some(id) = request_id::current() {
+        object.insert("request_id".into(), id.into());
+    }
+
+    let mut fields = Fields::default();
+    let _ = record.key_values().visit(&mut fields);
+    for (key, value) in fields.0 {
+        // Fields don't override the standard ones.
+        object.entry(key).or_insert(value);
+    }
+    Json::Object(object).to_string()
+}
+
+/// Collects key/value fields of a record, keeping numbers and booleans as such.
+#[derive(Default)]
+struct Fields(Vec<(String, Json)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(number) = value.to_i64() {
+            number.into()
+        } else if let Some(number) = value.to_u64() {
+            number.into()
+        } else if let Some(flag) = value.to_bool() {
+            flag.into()
+        } else if let Some(number) = value.to_f64().and_then(serde_json::Number::from_f64) {
+            Json::Number(number)
+        } else {
+            Json::String(value.to_string())
+        };
+        self.0.push((key.to_string(), value));
+        Ok(())
+    }
+}
+
+/// Log file, which is renamed with a timestamp suffix once it exceeds its size or its period ends.
+/// Only `retain` latest rotated files are kept.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: Option<u64>,
+    interval: Interval,
+    period: String,
+    retain: usize,
+    /// A record is written in several parts, the file is rotated between records only.
+    mid_line: bool,
+}
+
+impl RotatingFile {
+    fn open(
+        path: PathBuf,
+        max_size: Option<u64>,
+        interval: Interval,
+        retain: usize,
+    ) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        // A file left from a previous run belongs to the period it was last written in.
+        let modified = metadata
+            .modified()
+            .map(DateTime::<Local>::from)
+            .unwrap_or_else(|_| Local::now());
+        Ok(Self {
+            period: interval.period(&modified),
+            size: metadata.len(),
+            path,
+            file,
+            max_size,
+            interval,
+            retain,
+            mid_line: false,
+        })
+    }
+
+    fn should_rotate(&self, incoming: usize, now: &DateTime<Local>) -> bool {
+        let too_big = self
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + incoming as u64 > max);
+        too_big || self.interval.period(now) != self.period
+    }
+
+    fn rotate(&mut self, now: &DateTime<Local>) -> io::Result<()> {
+        self.file.flush()?;
+        let mut rotated = self.rotated_path(now, 0);
+        let mut attempt = 0;
+        while rotated.exists() {
+            attempt += 1;
+            rotated = self.rotated_path(now, attempt);
+        }
+        fs::rename(&self.path, &rotated)?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.period = self.interval.period(now);
+        self.remove_old()
+    }
+
+    /// `app.log` becomes `app.log.20211001-120000`, or `app.log.20211001-120000.1` on clash.
+    fn rotated_path(&self, now: &DateTime<Local>, attempt: u32) -> PathBuf {
+        let mut name = self.path.as_os_str().to_owned();
+        name.push(now.format(".%Y%m%d-%H%M%S").to_string());
+        if attempt > 0 {
+            name.push(format!(".{}", attempt));
+        }
+        PathBuf::from(name)
+    }
+
+    fn remove_old(&self) -> io::Result<()> {
+        let dir = match self.path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        let prefix = match self.path.file_name() {
+            Some(name) => format!("{}.", name.to_string_lossy()),
+            None => return Ok(()),
+        };
+
+        let mut rotated: Vec<(std::time::SystemTime, PathBuf)> = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+            .filter_map(|entry| {
+                let modified = entry.metadata().and_then(|metadata| metadata.modified());
+                modified.ok().map(|modified| (modified, entry.path()))
+            })
+            .collect();
+        rotated.sort();
+        let excess = rotated.len().saturating_sub(self.retain);
+        for (_, path) in rotated.into_iter().take(excess) {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let now = Local::now();
+        if !self.mid_line && self.should_rotate(buf.len(), &now) {
+            // Logging goes on into the current file if it can't be rotated.
+            if let Err(e) = self.rotate(&now) {
+                eprintln!("Can't rotate log file {}: {}", self.path.display(), e);
+                self.period = self.interval.period(&now);
+            }
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        self.mid_line = !buf[..written].ends_with(b"\n");
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use log::{Level, Log, Metadata};
+
+    /// Empty directory of the test in the system temporary one.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("boards_back-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn enabled(logger: &dyn Log, target: &str, level: Level) -> bool {
+        logger.enabled(&Metadata::builder().target(target).level(level).build())
+    }
+
+    #[test]
+    fn rotates_by_size_keeping_latest_files() {
+        let dir = temp_dir("size");
+        let path = dir.join("app.log");
+        let mut file = RotatingFile::open(path.clone(), Some(100), Interval::Never, 2).unwrap();
+        for i in 0..10 {
+            let record = format!("record {:02} {}\n", i, "x".repeat(29));
+            file.write_all(record.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+
+        // Two records of 40 bytes fit a file, so 5 files were written and 2 rotated ones kept.
+        let mut names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names.len(), 3, "{:?}", names);
+        assert_eq!(names[0], "app.log");
+        assert!(names[1..].iter().all(|name| name.starts_with("app.log.")));
+        let current = fs::read_to_string(&path).unwrap();
+        assert!(current.starts_with("record 08 ") && current.contains("record 09 "));
+        assert_eq!(current.len(), 80);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rotates_when_period_ends() {
+        let dir = temp_dir("interval");
+        let now = Local::now();
+        let daily = RotatingFile::open(dir.join("daily.log"), None, Interval::Daily, 1).unwrap();
+        assert!(!daily.should_rotate(10, &now));
+        assert!(daily.should_rotate(10, &(now + Duration::days(1))));
+        let never = RotatingFile::open(dir.join("never.log"), None, Interval::Never, 1).unwrap();
+        assert!(!never.should_rotate(10, &(now + Duration::days(1))));
+        // An empty file isn't rotated for size, even for a record bigger than the limit.
+        let sized = RotatingFile::open(dir.join("sized.log"), Some(1), Interval::Never, 1).unwrap();
+        assert!(!sized.should_rotate(10, &now));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parses_level_overrides() {
+        let dispatch = with_levels(fern::Dispatch::new(), "info, boards_back::db=debug").unwrap();
+        let (_, logger) = dispatch
+            .chain(Box::new(io::sink()) as Box<dyn Write + Send>)
+            .into_log();
+        assert!(enabled(&*logger, "boards_back::db", Level::Debug));
+        assert!(enabled(&*logger, "boards_back::db::sql", Level::Debug));
+        assert!(!enabled(&*logger, "boards_back::db", Level::Trace));
+        assert!(!enabled(&*logger, "boards_back::handlers", Level::Debug));
+        assert!(enabled(&*logger, "boards_back::handlers", Level::Info));
+
+        assert!(with_levels(fern::Dispatch::new(), "info,actix_web=loud").is_err());
+    }
+}
@@ -1,99 +1,245 @@
+mod auth;
 pub mod boards;
+mod config;
 mod db;
 mod errors;
+mod events;
 mod handlers;
+mod health;
+mod logging;
+mod members;
+mod metrics;
 mod models;
+mod rank;
 pub mod rate_lim;
+mod request_id;
+mod socket;
+mod stages;
 mod tasks;
+mod telemetry;
+mod users;
+mod validation;
 
+use crate::auth::Auth;
 use crate::boards::Boards;
+use crate::config::{Config, Storage};
 use crate::db::cached::Cached;
+use crate::db::measured::Measured;
+use crate::db::memory::Memory;
 use crate::db::mongo::Mongo;
+use crate::db::sql::Sql;
+use crate::db::{BoardsDatabase, TasksDatabase, UsersDatabase};
+use crate::health::{Dependency, Health, Startup};
+use crate::members::Members;
+use crate::metrics::Metrics;
+use crate::rate_lim::{Limiter, RateLimiter};
+use crate::request_id::RequestId;
+use crate::stages::Stages;
 use crate::tasks::Tasks;
+use crate::users::Users;
 use actix_web::{web, App, HttpServer};
-use std::env;
 use std::sync::Arc;
-use crate::rate_lim::RateLimiter;
+use std::time::Duration;
+
+/// Default format of `Logger` with the request id, as access records are made
+/// after the request is handled.
+const ACCESS_LOG_FORMAT: &str = r#"%a "%{request_line}xi" %s %b "%{Referer}i" "%{User-Agent}i" %T %{x-request-id}i"#;
 
 #[actix_web::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    dotenv::dotenv()?;
-    init()?;
-
-    let mongo_connection_str = env::var("MONGO_CONNECTION")?;
-    let client = mongodb::Client::with_uri_str(mongo_connection_str).await?;
-    let mongo_db = Mongo::new(client);
-
-    let redis_connection_str = env::var("REDIS_CONNECTION")?;
-    let redis_client = redis::Client::open(redis_connection_str)?;
-    let connection_manager = redis_client.get_tokio_connection_manager().await?;
-    let rate_limiter = RateLimiter::new(connection_manager);
-    let database = Box::new(Cached::new(mongo_db, redis_client));
-
-
-
-    let boards = Arc::new(Boards::new(database.clone()));
-    let tasks = Arc::new(Tasks::new(database));
-
+    dotenv::dotenv().ok();
+    logging::init()?;
+    let tracer_provider = telemetry::init()?;
+
+    let config = Config::load().map_err(|e| {
+        log::error!("{}", e);
+        e
+    })?;
+    log::debug!("Configuration: {:?}", config);
+
+    let startup = if config.wait_for_dependencies {
+        Startup::waiting(Duration::from_secs(config.startup_timeout))
+    } else {
+        Startup::immediate()
+    };
+    let mut dependencies = Vec::new();
+
+    let redis_client = match &config.redis_connection {
+        Some(redis_connection_str) => {
+            let redis_client = redis::Client::open(redis_connection_str.as_str())?;
+            let redis = Dependency::Redis(redis_client.clone());
+            // Otherwise only the rate limiter connects on start, failing it if Redis is down;
+            // its fallback covers Redis going down later.
+            if config.wait_for_dependencies {
+                startup.connect("Redis", || redis.ping()).await?;
+            }
+            dependencies.push(redis);
+            Some(redis_client)
+        }
+        None => None,
+    };
+
+    let auth = Arc::new(Auth::new(&config.auth));
+    let rate_limiter = match &redis_client {
+        Some(redis_client) if config.rate_limit.enabled => {
+            let connection_manager = startup
+                .connect("Redis", || async {
+                    Ok(redis_client.get_tokio_connection_manager().await?)
+                })
+                .await?;
+            RateLimiter::new(Limiter::new(
+                connection_manager,
+                config.rate_limit.clone(),
+                Arc::clone(&auth),
+            ))
+        }
+        _ => RateLimiter::disabled(),
+    };
+
+    let cache = redis_client.filter(|_| config.cache);
+    let services = match config.storage {
+        Storage::Mongo => {
+            let mongo_connection_str = config.mongo_connection.as_deref().unwrap_or_default();
+            let (client, mongo) = startup
+                .connect("MongoDB", || async {
+                    let client = mongodb::Client::with_uri_str(mongo_connection_str).await?;
+                    let mut mongo = Mongo::new(client.clone());
+                    mongo.detect_transactions().await?;
+                    mongo.create_indexes().await?;
+                    mongo.rank_legacy_tasks().await?;
+                    Ok((client, mongo))
+                })
+                .await?;
+            dependencies.push(Dependency::Mongo(client));
+            services(mongo, cache, &auth, &config)
+        }
+        Storage::Sql => {
+            let sql_connection_str = config.sql_connection.as_deref().unwrap_or_default();
+            let sql = startup
+                .connect("SQL database", || Sql::connect(sql_connection_str))
+                .await?;
+            dependencies.push(Dependency::Sql(sql.pool().clone()));
+            services(sql, cache, &auth, &config)
+        }
+        Storage::Memory => {
+            log::warn!("Using in-memory storage: data will be lost on restart");
+            services(Memory::new(config.event_log_size), cache, &auth, &config)
+        }
+    };
+
+    let health = Arc::new(Health::new(dependencies));
+
+    if !config.cache && config.storage != Storage::Memory {
+        log::warn!("Cache is disabled: board updates subscription is unavailable");
+    }
 
-    HttpServer::new(move || {
+    let mut server = HttpServer::new(move || {
         App::new()
+            // users
+            .service(handlers::register)
+            .service(handlers::login)
+            .service(handlers::refresh)
+            .service(handlers::me)
             // boards
             .service(handlers::read_boards)
             .service(handlers::create_board)
             .service(handlers::read_board)
             .service(handlers::update_board)
+            .service(handlers::patch_board)
             .service(handlers::delete_board)
             .service(handlers::subscribe_board_changes)
+            .service(handlers::board_socket)
+            // stages
+            .service(handlers::create_stage)
+            .service(handlers::reorder_stages)
+            .service(handlers::rename_stage)
+            .service(handlers::delete_stage)
+            // members
+            .service(handlers::read_members)
+            .service(handlers::invite_member)
+            .service(handlers::change_member_role)
+            .service(handlers::remove_member)
             // tasks
             .service(handlers::read_tasks)
             .service(handlers::read_tasks)
             .service(handlers::create_task)
             .service(handlers::read_task)
             .service(handlers::update_task)
+            .service(handlers::patch_task)
+            .service(handlers::move_task)
             .service(handlers::delete_task)
+            // monitoring
+            .service(handlers::liveness)
+            .service(handlers::readiness)
+            .service(handlers::read_metrics)
             // config
-            .wrap(actix_web::middleware::Logger::default())
+            .app_data(web::JsonConfig::default().error_handler(handlers::input_error))
+            .app_data(web::QueryConfig::default().error_handler(handlers::input_error))
+            .app_data(web::PathConfig::default().error_handler(handlers::input_error))
+            .wrap(
+                actix_web::middleware::Logger::new(ACCESS_LOG_FORMAT)
+                    .custom_request_replace("request_line", auth::redacted_request_line),
+            )
             .wrap(rate_limiter.clone())
-            .app_data(web::Data::new(Arc::clone(&boards)))
-            .app_data(web::Data::new(Arc::clone(&tasks)))
-    })
-    .bind("127.0.0.1:9000")?
-    .run()
-    .await?;
+            .wrap(Metrics)
+            .wrap(RequestId)
+            .app_data(web::Data::new(Arc::clone(&services.boards)))
+            .app_data(web::Data::new(Arc::clone(&services.tasks)))
+            .app_data(web::Data::new(Arc::clone(&services.stages)))
+            .app_data(web::Data::new(Arc::clone(&services.members)))
+            .app_data(web::Data::new(Arc::clone(&services.users)))
+            .app_data(web::Data::new(Arc::clone(&auth)))
+            .app_data(web::Data::new(Arc::clone(&health)))
+    });
+
+    if let Some(workers) = config.workers {
+        server = server.workers(workers);
+    }
+
+    server.bind(config.bind_address())?.run().await?;
+
+    if let Some(tracer_provider) = tracer_provider {
+        // Export blocks until the collector answers.
+        actix_web::rt::task::spawn_blocking(move || telemetry::shutdown(tracer_provider)).await?;
+    }
 
     Ok(())
 }
 
-fn init() -> Result<(), fern::InitError> {
-    let log_level = env::var("LOG_LEVEL").unwrap_or_else(|_| "INFO".into());
-    let log_level = log_level.parse().unwrap_or(log::LevelFilter::Info);
-
-    let mut builder = fern::Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "[{}][{}][{}] {}",
-                chrono::Local::now().format("%H:%M:%S"),
-                record.target(),
-                record.level(),
-                message
-            ))
-        })
-        .level(log_level)
-        .chain(std::io::stderr());
+#[derive(Clone)]
+struct Services {
+    boards: Arc<Boards>,
+    tasks: Arc<Tasks>,
+    stages: Arc<Stages>,
+    members: Arc<Members>,
+    users: Arc<Users>,
+}
 
-    if let Ok(log_file) = env::var("LOG_FILE") {
-        let log_file = std::fs::File::create(log_file)?;
-        builder = builder.chain(log_file);
+impl Services {
+    fn new<T>(db: T, auth: &Arc<Auth>) -> Self
+    where
+        T: BoardsDatabase + TasksDatabase + UsersDatabase + Clone + 'static,
+    {
+        Self {
+            boards: Arc::new(Boards::new(Box::new(db.clone()))),
+            tasks: Arc::new(Tasks::new(Box::new(db.clone()), Box::new(db.clone()))),
+            stages: Arc::new(Stages::new(Box::new(db.clone()), Box::new(db.clone()))),
+            members: Arc::new(Members::new(Box::new(db.clone()), Box::new(db.clone()))),
+            users: Arc::new(Users::new(Box::new(db), Arc::clone(auth))),
+        }
     }
+}
 
-    builder.apply()?;
-
-    log::trace!("TRACE output enabled");
-    log::debug!("DEBUG output enabled");
-    log::info!("INFO output enabled");
-    log::warn!("WARN output enabled");
-    log::error!("ERROR output enabled");
-
-    Ok(())
+/// Builds services on top of measured storage, optionally wrapped with Redis cache and pub/sub.
+fn services<T>(db: T, cache: Option<redis::Client>, auth: &Arc<Auth>, config: &Config) -> Services
+where
+    T: BoardsDatabase + TasksDatabase + UsersDatabase + Clone + 'static,
+{
+    let db = Measured::new(db, config.storage.name());
+    match cache {
+        Some(redis_client) => {
+            Services::new(Cached::new(db, redis_client, config.event_log_size), auth)
+        }
+        None => Services::new(db, auth),
+    }
 }
@@ -0,0 +1,132 @@
+use crate::db::{BoardsDatabase, UsersDatabase};
+use crate::errors::{CustomError, CustomResult};
+use crate::models::{Board, Member, MemberInfo, NewMember, Role};
+use mongodb::bson::oid::ObjectId;
+use std::str::FromStr;
+
+pub struct Members {
+    boards: Box<dyn BoardsDatabase>,
+    users: Box<dyn UsersDatabase>,
+}
+
+impl Members {
+    pub fn new(boards: Box<dyn BoardsDatabase>, users: Box<dyn UsersDatabase>) -> Self {
+        Self { boards, users }
+    }
+
+    async fn authorize(
+        &self,
+        board_id: &str,
+        user_id: &ObjectId,
+        required: Role,
+    ) -> CustomResult<Board> {
+        let mut board = self.boards.read_board(board_id).await?;
+        board.authorize(user_id, required)?;
+        board.migrate_members();
+        Ok(board)
+    }
+
+    async fn member_info(&self, member: &Member) -> CustomResult<MemberInfo> {
+        let user = self.users.read_user(&member.user_id.to_hex()).await?;
+        Ok(MemberInfo {
+            user_id: member.user_id,
+            username: user.username,
+            role: member.role,
+        })
+    }
+
+    pub async fn read_members(
+        &self,
+        board_id: &str,
+        user_id: &ObjectId,
+    ) -> CustomResult<Vec<MemberInfo>> {
+        let board = self.authorize(board_id, user_id, Role::Viewer).await?;
+        let mut members = Vec::with_capacity(board.members.len());
+        for member in board.members.iter() {
+            members.push(self.member_info(member).await?);
+        }
+        Ok(members)
+    }
+
+    pub async fn invite(
+        &self,
+        board_id: &str,
+        invitation: NewMember,
+        user_id: &ObjectId,
+    ) -> CustomResult<MemberInfo> {
+        let mut board = self.authorize(board_id, user_id, Role::Owner).await?;
+        let invited = self.users.read_user_by_name(&invitation.username).await?;
+        let invited_id = invited
+            .id
+            .ok_or_else(|| CustomError::InternalError("user has no id".into()))?;
+        if board.role_of(&invited_id).is_some() {
+            return Err(CustomError::Conflict(format!(
+                "{} is already a member",
+                invitation.username
+            )));
+        }
+
+        let member = Member {
+            user_id: invited_id,
+            role: invitation.role,
+        };
+        board.members.push(member.clone());
+        self.boards.update_board(board_id, board).await?;
+        self.member_info(&member).await
+    }
+
+    pub async fn change_role(
+        &self,
+        board_id: &str,
+        member_id: &str,
+        role: Role,
+        user_id: &ObjectId,
+    ) -> CustomResult<MemberInfo> {
+        let mut board = self.authorize(board_id, user_id, Role::Owner).await?;
+        let member_id = ObjectId::from_str(member_id)?;
+        let member = board.members.iter_mut().find(|m| m.user_id == member_id);
+        let member = member.ok_or_else(|| member_not_found(&member_id))?;
+        member.role = role;
+        let member = member.clone();
+
+        check_has_owner(&board)?;
+        self.boards.update_board(board_id, board).await?;
+        self.member_info(&member).await
+    }
+
+    /// Removes member from the board. Owners remove anyone, other members only leave themselves.
+    pub async fn remove(
+        &self,
+        board_id: &str,
+        member_id: &str,
+        user_id: &ObjectId,
+    ) -> CustomResult<()> {
+        let member_id = ObjectId::from_str(member_id)?;
+        let required = if &member_id == user_id {
+            Role::Viewer
+        } else {
+            Role::Owner
+        };
+        let mut board = self.authorize(board_id, user_id, required).await?;
+        let position = board.members.iter().position(|m| m.user_id == member_id);
+        board
+            .members
+            .remove(position.ok_or_else(|| member_not_found(&member_id))?);
+
+        check_has_owner(&board)?;
+        self.boards.update_board(board_id, board).await?;
+        Ok(())
+    }
+}
+
+fn check_has_owner(board: &Board) -> CustomResult<()> {
+    if board.members.iter().any(|m| m.role == Role::Owner) {
+        Ok(())
+    } else {
+        Err(CustomError::BadRequest("board must have an owner".into()))
+    }
+}
+
+fn member_not_found(id: &ObjectId) -> CustomError {
+    CustomError::NotFound(format!("member with id: {}", id))
+}
@@ -0,0 +1,182 @@
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::StatusCode;
+use futures::future::LocalBoxFuture;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, Encoder,
+    HistogramVec, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+use std::future::Future;
+use std::rc::Rc;
+use std::time::Instant;
+
+/// Buckets of storage call durations, seconds: calls are much faster than whole requests.
+const STORAGE_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+lazy_static! {
+    static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "http_requests_total",
+        "HTTP requests by route pattern, method and status",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    static ref HTTP_DURATION: HistogramVec = register_histogram_vec!(
+        "http_request_duration_seconds",
+        "Time until the response head is ready, by route pattern and method",
+        &["method", "route"]
+    )
+    .unwrap();
+    static ref ERRORS: IntCounterVec = register_int_counter_vec!(
+        "errors_total",
+        "Errors answered over HTTP or WebSocket, by CustomError variant",
+        &["variant"]
+    )
+    .unwrap();
+    static ref CACHE_READS: IntCounterVec = register_int_counter_vec!(
+        "cache_reads_total",
+        "Reads of boards and tasks from Redis cache, by entity and hit or miss",
+        &["entity", "result"]
+    )
+    .unwrap();
+    static ref RATE_LIMIT_REJECTIONS: IntCounterVec = register_int_counter_vec!(
+        "rate_limit_rejections_total",
+        "Requests rejected by the rate limiter, by route group",
+        &["group"]
+    )
+    .unwrap();
+    static ref RATE_LIMIT_FALLBACKS: IntCounterVec = register_int_counter_vec!(
+        "rate_limit_fallbacks_total",
+        "Times the rate limiter fallback took over from unreachable Redis, by fallback",
+        &["fallback"]
+    )
+    .unwrap();
+    static ref SUBSCRIBERS: IntGaugeVec = register_int_gauge_vec!(
+        "board_subscribers",
+        "Open board event streams, by transport",
+        &["transport"]
+    )
+    .unwrap();
+    static ref STORAGE_DURATION: HistogramVec = register_histogram_vec!(
+        "storage_call_duration_seconds",
+        "Duration of calls to storage and Redis, by backend and operation",
+        &["backend", "operation"],
+        STORAGE_BUCKETS.to_vec()
+    )
+    .unwrap();
+}
+
+/// Renders all metrics in Prometheus text format.
+pub fn render() -> Result<String, prometheus::Error> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(String::from_utf8_lossy(&buffer).into_owned())
+}
+
+pub fn error(variant: &str) {
+    ERRORS.with_label_values(&[variant]).inc();
+}
+
+pub fn cache_read(entity: &str, hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    CACHE_READS.with_label_values(&[entity, result]).inc();
+}
+
+pub fn rate_limit_rejection(group: &str) {
+    RATE_LIMIT_REJECTIONS.with_label_values(&[group]).inc();
+}
+
+pub fn rate_limit_fallback(fallback: &str) {
+    RATE_LIMIT_FALLBACKS.with_label_values(&[fallback]).inc();
+}
+
+/// Measures duration of a storage or Redis call, also when it's cancelled e.g. by a timeout.
+pub async fn timed<F: Future>(backend: &str, operation: &str, future: F) -> F::Output {
+    let _timer = STORAGE_DURATION
+        .with_label_values(&[backend, operation])
+        .start_timer();
+    future.await
+}
+
+/// Counts an open board event stream until dropped.
+pub struct Subscriber {
+    transport: &'static str,
+}
+
+impl Subscriber {
+    pub fn new(transport: &'static str) -> Self {
+        SUBSCRIBERS.with_label_values(&[transport]).inc();
+        Self { transport }
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        SUBSCRIBERS.with_label_values(&[self.transport]).dec();
+    }
+}
+
+/// Counts requests and measures their duration per route pattern, like `/boards/{board_id}`,
+/// so ids don't multiply series. Requests matching no route share `unmatched`.
+#[derive(Clone, Default)]
+pub struct Metrics;
+
+impl<S, B> Transform<S, ServiceRequest> for Metrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = S::Error;
+    type Transform = MetricsMiddleware<S>;
+    type InitError = ();
+    type Future = futures::future::Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        futures::future::ready(Ok(MetricsMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct MetricsMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for MetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = S::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let method = req.method().to_string();
+        let route = req.match_pattern().unwrap_or_else(|| "unmatched".into());
+        let started = Instant::now();
+        Box::pin(async move {
+            let result = service.call(req).await;
+            let status = match &result {
+                Ok(res) => res.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+            observe(&method, &route, status, started);
+            result
+        })
+    }
+}
+
+fn observe(method: &str, route: &str, status: StatusCode, started: Instant) {
+    HTTP_REQUESTS
+        .with_label_values(&[method, route, status.as_str()])
+        .inc();
+    HTTP_DURATION
+        .with_label_values(&[method, route])
+        .observe(started.elapsed().as_secs_f64());
+}
@@ -1,28 +1,581 @@
+use crate::errors::{CustomError, CustomResult};
+use crate::validation::{FieldError, Validator};
 use mongodb::bson::oid::ObjectId;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::str::FromStr;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Board {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     pub id: Option<ObjectId>,
     pub name: String,
+    /// Empty if not set, so a merge patch with `null` clears it.
+    #[serde(default)]
     pub description: String,
+    /// Ordered workflow stages. Boards stored before stages were introduced get the defaults.
+    #[serde(default = "Stage::defaults")]
+    pub stages: Vec<Stage>,
+    /// User who created the board. Set by the server, missing for boards created anonymously.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<ObjectId>,
+    /// Users having access to the board. Managed by the server.
+    #[serde(default)]
+    pub members: Vec<Member>,
+    /// Increased by every change, sent as `ETag`. Managed by the server.
+    #[serde(default)]
+    pub version: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl Board {
+    pub fn stage(&self, stage_id: &str) -> Option<&Stage> {
+        self.stages.iter().find(|stage| stage.id == stage_id)
+    }
+
+    /// Role of the user on the board, if any.
+    pub fn role_of(&self, user_id: &ObjectId) -> Option<Role> {
+        if self.members.is_empty() && self.created_by.as_ref() == Some(user_id) {
+            // Not migrated yet, see `migrate_members`.
+            return Some(Role::Owner);
+        }
+        let member = self.members.iter().find(|member| &member.user_id == user_id);
+        member.map(|member| member.role)
+    }
+
+    /// Checks that user has at least `required` role on the board.
+    /// Boards are hidden from non-members, so those get `NotFound`.
+    pub fn authorize(&self, user_id: &ObjectId, required: Role) -> CustomResult<()> {
+        match self.role_of(user_id) {
+            Some(role) if role >= required => Ok(()),
+            Some(role) => Err(CustomError::Forbidden(format!(
+                "{} role is required, user is {}",
+                required.as_str(),
+                role.as_str()
+            ))),
+            None => Err(CustomError::NotFound(format!(
+                "board with id: {}",
+                self.id.map(|id| id.to_hex()).unwrap_or_default()
+            ))),
+        }
+    }
+
+    /// Boards created before membership was introduced have no members: their creator becomes owner.
+    pub fn migrate_members(&mut self) {
+        if self.members.is_empty() {
+            if let Some(creator) = self.created_by {
+                self.members.push(Member {
+                    user_id: creator,
+                    role: Role::Owner,
+                });
+            }
+        }
+    }
+}
+
+/// Access level on a board. Every role includes permissions of the previous ones.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Reads the board and its tasks, subscribes on updates.
+    Viewer,
+    /// Creates, changes and deletes tasks.
+    Editor,
+    /// Changes and deletes the board, manages stages and members.
+    Owner,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Viewer => "viewer",
+            Self::Editor => "editor",
+            Self::Owner => "owner",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = CustomError;
+
+    fn from_str(s: &str) -> CustomResult<Self> {
+        match s {
+            "viewer" => Ok(Self::Viewer),
+            "editor" => Ok(Self::Editor),
+            "owner" => Ok(Self::Owner),
+            _ => Err(CustomError::InternalError(format!("unknown role: {}", s))),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Member {
+    pub user_id: ObjectId,
+    pub role: Role,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MemberInfo {
+    pub user_id: ObjectId,
+    pub username: String,
+    pub role: Role,
+}
+
+/// Invitation of a user to a board.
+#[derive(Deserialize, Debug)]
+pub struct NewMember {
+    pub username: String,
+    pub role: Role,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MemberRole {
+    pub role: Role,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Stage {
+    pub id: String,
+    pub name: String,
+}
+
+impl Stage {
+    /// Stages every board had before they became configurable.
+    /// Their ids match former fixed stage names, so existing tasks stay valid.
+    pub fn defaults() -> Vec<Self> {
+        vec![
+            Self::new("Backlog", "Backlog"),
+            Self::new("InProgress", "In progress"),
+            Self::new("Complete", "Complete"),
+        ]
+    }
+
+    pub fn new(id: &str, name: &str) -> Self {
+        Self {
+            id: id.into(),
+            name: name.into(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StageName {
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct DeleteStageQuery {
+    /// Stage to move tasks of deleted stage to. First remaining stage by default.
+    pub move_to: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct SocketQuery {
+    /// Id of the last event the client has seen, events after it are replayed.
+    pub last_event_id: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Task {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     pub id: Option<ObjectId>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub board_id: Option<ObjectId>,
     pub name: String,
+    /// Empty if not set, like the board's one.
+    #[serde(default)]
     pub description: String,
-    pub stage: TaskStage,
+    /// Id of board's stage. Empty means the first stage of the board.
+    #[serde(default)]
+    pub stage: String,
+    /// Position within the stage, see [`crate::rank`]. Managed by the server.
+    #[serde(default)]
+    pub rank: String,
+    /// User who created the task. Set by the server, missing for tasks created anonymously.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<ObjectId>,
+    /// Increased by every change, sent as `ETag`. Managed by the server.
+    #[serde(default)]
+    pub version: u64,
+}
+
+/// Registered user. Carries password hash, so API returns [`UserInfo`] instead.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct User {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub username: String,
+    pub password_hash: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct UserInfo {
+    #[serde(rename = "_id")]
+    pub id: Option<ObjectId>,
+    pub username: String,
+}
+
+impl From<User> for UserInfo {
+    fn from(user: User) -> Self {
+        Self {
+            id: user.id,
+            username: user.username,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshToken {
+    pub refresh_token: String,
+}
+
+/// Where to move a task. Neighbours are ids of tasks in the target stage.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct MoveTask {
+    /// Target stage. Current stage of the task by default.
+    pub stage: Option<String>,
+    /// Task to place the moved one right after.
+    pub after: Option<String>,
+    /// Task to place the moved one right before.
+    pub before: Option<String>,
+}
+
+/// Versions the client expects an entity to have, taken from `If-Match`.
+/// `None` matches any version: the header is missing or `*`.
+#[derive(Debug, Clone, Default)]
+pub struct Precondition(pub Option<Vec<u64>>);
+
+impl Precondition {
+    pub fn check(&self, version: u64) -> CustomResult<()> {
+        match &self.0 {
+            Some(versions) if !versions.contains(&version) => Err(CustomError::PreconditionFailed(
+                format!("current version is {}", version),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Partial update of an entity, applied to its JSON representation.
+#[derive(Debug, Clone)]
+pub enum Patch {
+    /// RFC 7396 JSON Merge Patch, `application/merge-patch+json`.
+    Merge(Value),
+    /// RFC 6902 JSON Patch, `application/json-patch+json`.
+    Json(json_patch::Patch),
+}
+
+impl Patch {
+    /// Applies patch to the entity. Returns the patched one and names of its changed
+    /// top-level fields, which must all be `editable`.
+    pub fn apply<T>(&self, entity: &T, editable: &[&str]) -> CustomResult<(T, Vec<String>)>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        let original = serde_json::to_value(entity)?;
+        let mut patched = original.clone();
+        match self {
+            Self::Merge(patch) => json_patch::merge(&mut patched, patch),
+            Self::Json(patch) => json_patch::patch(&mut patched, patch).map_err(|e| {
+                match e.kind {
+                    json_patch::PatchErrorKind::TestFailed => {
+                        CustomError::Conflict(format!("patch test failed: {}", e))
+                    }
+                    _ => CustomError::Validation(vec![FieldError {
+                        field: e.path.clone(),
+                        message: e.kind.to_string(),
+                    }]),
+                }
+            })?,
+        }
+
+        let (original_fields, patched_fields) = match (&original, &patched) {
+            (Value::Object(original), Value::Object(patched)) => (original, patched),
+            _ => return Err(CustomError::BadRequest("patch must keep an object".into())),
+        };
+        let mut changed: Vec<String> = Vec::new();
+        let mut validator = Validator::default();
+        for field in original_fields.keys().chain(patched_fields.keys()) {
+            if original_fields.get(field) != patched_fields.get(field) && !changed.contains(field) {
+                if !editable.contains(&field.as_str()) {
+                    validator.error(field.as_str(), "can't be patched");
+                }
+                changed.push(field.clone());
+            }
+        }
+        validator.finish()?;
+
+        let patched = serde_json::from_value(patched)
+            .map_err(|e| CustomError::BadRequest(format!("invalid patched entity: {}", e)))?;
+        Ok((patched, changed))
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
-pub enum TaskStage {
-    Backlog,
-    InProgress,
-    Complete,
+/// Board fields changed by a partial update. Missing ones are kept as they are.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct BoardPatch {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+impl BoardPatch {
+    /// Fields a client may patch. Stages and members have their own endpoints.
+    pub const EDITABLE: &'static [&'static str] = &["name", "description"];
+
+    /// Takes `changed` fields from the patched board.
+    pub fn new(board: Board, changed: &[String]) -> Self {
+        let take = |field: &str, value: String| changed.iter().any(|c| c == field).then_some(value);
+        Self {
+            name: take("name", board.name),
+            description: take("description", board.description),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none() && self.description.is_none()
+    }
+
+    pub fn apply(&self, board: &mut Board) {
+        if let Some(name) = &self.name {
+            board.name = name.clone();
+        }
+        if let Some(description) = &self.description {
+            board.description = description.clone();
+        }
+    }
+}
+
+/// Task fields changed by a partial update. Missing ones are kept as they are.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct TaskPatch {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stage: Option<String>,
+    /// Set by the server when the task is put to another stage.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rank: Option<String>,
+}
+
+impl TaskPatch {
+    /// Fields a client may patch. Position is changed by moving.
+    pub const EDITABLE: &'static [&'static str] = &["name", "description", "stage"];
+
+    /// Takes `changed` fields from the patched task.
+    pub fn new(task: Task, changed: &[String]) -> Self {
+        let take = |field: &str, value: String| changed.iter().any(|c| c == field).then_some(value);
+        Self {
+            name: take("name", task.name),
+            description: take("description", task.description),
+            stage: take("stage", task.stage),
+            rank: take("rank", task.rank),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none()
+            && self.description.is_none()
+            && self.stage.is_none()
+            && self.rank.is_none()
+    }
+
+    pub fn apply(&self, task: &mut Task) {
+        if let Some(name) = &self.name {
+            task.name = name.clone();
+        }
+        if let Some(description) = &self.description {
+            task.description = description.clone();
+        }
+        if let Some(stage) = &self.stage {
+            task.stage = stage.clone();
+        }
+        if let Some(rank) = &self.rank {
+            task.rank = rank.clone();
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SortField {
+    /// Creation order.
+    #[default]
+    Id,
+    Name,
+    /// Manual order. Tasks only.
+    Rank,
+}
+
+#[derive(Deserialize, Debug, Copy, Clone, Eq, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Pagination, sorting and filtering shared by all listings.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ListQuery {
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+    /// Default depends on the listing.
+    pub sort: Option<SortField>,
+    pub order: SortOrder,
+    pub name_prefix: Option<String>,
+}
+
+impl ListQuery {
+    pub const DEFAULT_LIMIT: usize = 20;
+    pub const MAX_LIMIT: usize = 100;
+
+    pub fn sort(&self) -> SortField {
+        self.sort.unwrap_or_default()
+    }
+
+    pub fn limit(&self) -> usize {
+        let limit = self.limit.unwrap_or(Self::DEFAULT_LIMIT);
+        limit.clamp(1, Self::MAX_LIMIT)
+    }
+
+    /// Decoded cursor of the last item of the previous page.
+    pub fn cursor(&self) -> CustomResult<Option<Cursor>> {
+        let cursor = match &self.cursor {
+            Some(cursor) => Cursor::decode(cursor)?,
+            None => return Ok(None),
+        };
+        if cursor.sort != self.sort() {
+            return Err(CustomError::BadRequest(
+                "cursor was issued for another sort field".into(),
+            ));
+        }
+        Ok(Some(cursor))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BoardFilter {
+    /// Only boards the user is a member of.
+    pub member: Option<ObjectId>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct TaskFilter {
+    pub stage: Option<String>,
+}
+
+/// Opaque position in a sorted listing.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Cursor {
+    pub sort: SortField,
+    /// Value of the sort field, unless sorted by id.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    pub id: ObjectId,
+}
+
+impl Cursor {
+    pub fn after<T: Listable>(item: &T, sort: SortField) -> Option<Self> {
+        Some(Self {
+            sort,
+            key: item.sort_key(sort).map(String::from),
+            id: item.id()?,
+        })
+    }
+
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).expect("Cursor is always serializable");
+        base64::encode_config(json, base64::URL_SAFE_NO_PAD)
+    }
+
+    pub fn decode(encoded: &str) -> CustomResult<Self> {
+        let invalid = || CustomError::BadRequest("invalid cursor".into());
+        let json = base64::decode_config(encoded, base64::URL_SAFE_NO_PAD).map_err(|_| invalid())?;
+        serde_json::from_slice(&json).map_err(|_| invalid())
+    }
+}
+
+/// Items which can be listed page by page.
+pub trait Listable {
+    fn id(&self) -> Option<ObjectId>;
+    fn name(&self) -> &str;
+
+    /// Value of the sort field other than id.
+    fn sort_key(&self, sort: SortField) -> Option<&str>;
+}
+
+impl Listable for Board {
+    fn id(&self) -> Option<ObjectId> {
+        self.id
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sort_key(&self, sort: SortField) -> Option<&str> {
+        match sort {
+            SortField::Name => Some(&self.name),
+            SortField::Id | SortField::Rank => None,
+        }
+    }
+}
+
+impl Listable for Task {
+    fn id(&self) -> Option<ObjectId> {
+        self.id
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sort_key(&self, sort: SortField) -> Option<&str> {
+        match sort {
+            SortField::Name => Some(&self.name),
+            SortField::Rank => Some(&self.rank),
+            SortField::Id => None,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+impl<T: Listable> Page<T> {
+    /// Makes page from items fetched with limit one greater than requested,
+    /// so presence of the extra item tells that there is a next page.
+    pub fn new(mut items: Vec<T>, query: &ListQuery) -> Self {
+        let limit = query.limit();
+        let next_cursor = if items.len() > limit {
+            items.truncate(limit);
+            items.last().and_then(|last| Cursor::after(last, query.sort()))
+        } else {
+            None
+        };
+
+        Self {
+            items,
+            next_cursor: next_cursor.map(|cursor| cursor.encode()),
+        }
+    }
 }
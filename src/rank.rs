@@ -0,0 +1,157 @@
+//! Lexicographic ranks for manual ordering.
+//!
+//! A rank is a string of base 36 digits compared as a fraction `0.<digits>`.
+//! There is always a rank between any two different ones, so moving an item
+//! only changes the rank of the moved item.
+
+use crate::errors::{CustomError, CustomResult};
+
+const DIGITS: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+/// Returns rank strictly between `prev` and `next`.
+/// Missing `prev` means the beginning of the list, missing `next` means the end.
+/// Equal ranks, which tasks created at once may get, are followed by the returned one.
+pub fn between(prev: Option<&str>, next: Option<&str>) -> CustomResult<String> {
+    let prev = prev.unwrap_or_default();
+    if let Some(next) = next {
+        if prev > next {
+            return Err(CustomError::BadRequest(format!(
+                "can't place item between ranks '{}' and '{}'",
+                prev, next
+            )));
+        }
+    }
+
+    for rank in std::iter::once(prev).chain(next) {
+        let valid_digits = rank.bytes().all(|b| DIGITS.contains(&b));
+        if !valid_digits || rank.ends_with('0') {
+            return Err(CustomError::InternalError(format!("invalid rank: '{}'", rank)));
+        }
+    }
+
+    if next == Some(prev) {
+        // Right after both, before ranks extending them with a non-zero digit.
+        let extended = format!("{}1", prev);
+        return Ok(midpoint(prev.as_bytes(), Some(extended.as_bytes())));
+    }
+    Ok(midpoint(prev.as_bytes(), next.map(str::as_bytes)))
+}
+
+/// Returns `count` increasing ranks placing items to the end of the list, after `last`.
+pub fn after(last: Option<&str>, count: usize) -> CustomResult<Vec<String>> {
+    let prefix = between(last, None)?;
+    Ok((0..count).map(|position| prefix.clone() + &nth(position)).collect())
+}
+
+/// Rank of an item when a whole list is ranked at once: `1000000i`, `1000001i` and so on.
+/// Equal length keeps them ordered, the trailing digit keeps them valid.
+pub fn nth(position: usize) -> String {
+    format!("{}i", 1_000_000 + position)
+}
+
+fn midpoint(prev: &[u8], next: Option<&[u8]>) -> String {
+    if let Some(next) = next {
+        // Keep common prefix, padding `prev` with zeros.
+        let common = (0..next.len())
+            .take_while(|&i| prev.get(i).copied().unwrap_or(DIGITS[0]) == next[i])
+            .count();
+        if common > 0 {
+            let prefix = String::from_utf8_lossy(&next[..common]).into_owned();
+            let rest = prev.get(common..).unwrap_or_default();
+            return prefix + &midpoint(rest, Some(&next[common..]));
+        }
+    }
+
+    // First digits differ.
+    let prev_digit = prev.first().map_or(0, |&d| digit_value(d));
+    let next_digit = next.map_or(DIGITS.len(), |next| digit_value(next[0]));
+    if next_digit - prev_digit > 1 {
+        let middle = (prev_digit + next_digit).div_ceil(2);
+        return (DIGITS[middle] as char).to_string();
+    }
+
+    // First digits are consecutive.
+    match next {
+        Some(next) if next.len() > 1 => (next[0] as char).to_string(),
+        _ => {
+            let rest = prev.get(1..).unwrap_or_default();
+            (DIGITS[prev_digit] as char).to_string() + &midpoint(rest, None)
+        }
+    }
+}
+
+fn digit_value(digit: u8) -> usize {
+    DIGITS
+        .iter()
+        .position(|&d| d == digit)
+        .expect("Rank digits are validated")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn between_some(prev: &str, next: &str) -> String {
+        let rank = between(Some(prev), Some(next)).unwrap();
+        assert!(prev < rank.as_str() && rank.as_str() < next, "{} < {} < {}", prev, rank, next);
+        rank
+    }
+
+    #[test]
+    fn ranks_empty_list() {
+        assert_eq!(between(None, None).unwrap(), "i");
+    }
+
+    #[test]
+    fn ranks_list_ends() {
+        let last = between(Some("i"), None).unwrap();
+        assert!(last.as_str() > "i");
+        let first = between(None, Some("i")).unwrap();
+        assert!(first.as_str() < "i" && !first.is_empty());
+        assert!(between(Some("z"), None).unwrap().as_str() > "z");
+        assert!(between(None, Some("1")).unwrap().as_str() < "1");
+    }
+
+    #[test]
+    fn ranks_between_adjacent() {
+        assert_eq!(between_some("a", "b"), "ai");
+        assert_eq!(between_some("a", "a1"), "a0i");
+        assert_eq!(between_some("az", "b"), "azi");
+        // A trailing zero would make "a" and "a0" equal fractions.
+        assert!(matches!(between(Some("a"), Some("a0")), Err(CustomError::InternalError(_))));
+    }
+
+    #[test]
+    fn bisects_toward_one_side() {
+        let mut next = "i".to_string();
+        for _ in 0..100 {
+            next = between_some("h", &next);
+        }
+        let mut prev = "h".to_string();
+        for _ in 0..100 {
+            prev = between_some(&prev, "i");
+        }
+        assert!(next.len() < 50 && prev.len() < 50, "{} {}", next, prev);
+    }
+
+    #[test]
+    fn rejects_decreasing_ranks() {
+        assert!(matches!(between(Some("b"), Some("a")), Err(CustomError::BadRequest(_))));
+        assert!(matches!(between(Some("a1"), Some("a")), Err(CustomError::BadRequest(_))));
+    }
+
+    #[test]
+    fn follows_equal_ranks() {
+        let rank = between(Some("i"), Some("i")).unwrap();
+        assert!(rank.as_str() > "i" && rank.as_str() < "i1" && rank.as_str() < "j");
+    }
+
+    #[test]
+    fn ranks_after_last() {
+        let ranks = after(Some("i"), 3).unwrap();
+        assert_eq!(ranks.len(), 3);
+        assert!(ranks.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(ranks[0].as_str() > "i");
+        assert!(nth(9).len() == nth(10).len() && nth(9) < nth(10));
+    }
+}
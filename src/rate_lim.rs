@@ -1,155 +1,681 @@
+use crate::auth::{stream_token, Auth, TokenKind};
+use crate::config::{Quota, RateLimitAlgorithm, RateLimitConfig, RateLimitFallback};
 use crate::errors::{CustomError, CustomResult};
+use crate::metrics;
+use actix_web::body::BodySize;
 use actix_web::dev::{MessageBody, Service, ServiceRequest, ServiceResponse, Transform};
-use chrono::{Timelike, Utc};
-use core::future::Future;
+use actix_web::http::{HeaderName, HeaderValue, Method};
+use actix_web::web::Bytes;
+use chrono::Utc;
+use futures::future::LocalBoxFuture;
 use redis::aio::ConnectionManager;
-use std::marker::PhantomData;
-use std::net::SocketAddr;
+use redis::Script;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::IpAddr;
 use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 #[derive(Clone)]
 pub struct RateLimiter {
-    connection_manager: ConnectionManager,
+    limiter: Option<Arc<Limiter>>,
 }
 
 impl RateLimiter {
-    pub fn new(connection_manager: ConnectionManager) -> Self {
-        Self { connection_manager }
+    pub fn new(limiter: Limiter) -> Self {
+        Self {
+            limiter: Some(Arc::new(limiter)),
+        }
+    }
+
+    /// Limiter that lets every request through. Used when Redis is not available.
+    pub fn disabled() -> Self {
+        Self { limiter: None }
     }
 }
 
 impl<S, B> Transform<S, ServiceRequest> for RateLimiter
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
-    B: MessageBody,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
 {
-    type Response = S::Response;
+    type Response = ServiceResponse<LimitedBody<B>>;
     type Error = S::Error;
     type Transform = RateLimiterMiddleware<S>;
     type InitError = ();
     type Future = futures::future::Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        let mw = RateLimiterMiddleware::new(self.connection_manager.clone(), service);
+        let mw = RateLimiterMiddleware::new(self.limiter.clone(), service);
         futures::future::ready(Ok(mw))
     }
 }
 
 pub struct RateLimiterMiddleware<S> {
-    service: S,
-    connection_manager: ConnectionManager,
+    service: Rc<S>,
+    limiter: Option<Arc<Limiter>>,
+    /// Fallback buckets of this worker.
+    local: Rc<RefCell<LocalBuckets>>,
 }
 
 impl<S> RateLimiterMiddleware<S> {
-    pub fn new(connection_manager: ConnectionManager, service: S) -> Self {
+    pub fn new(limiter: Option<Arc<Limiter>>, service: S) -> Self {
         Self {
-            service,
-            connection_manager,
+            service: Rc::new(service),
+            limiter,
+            local: Default::default(),
         }
     }
 }
 
 impl<S, B> Service<ServiceRequest> for RateLimiterMiddleware<S>
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
-    B: MessageBody,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
 {
-    type Response = S::Response;
+    type Response = ServiceResponse<LimitedBody<B>>;
     type Error = S::Error;
-    type Future = RateLimiterFuture<S, B>;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
 
     actix_web::dev::forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let address = req.peer_addr();
-        let fut = self.service.call(req);
-        let connections_result = futures::executor::block_on(get_connections_count(
-            address,
-            self.connection_manager.clone(),
-        ));
-        RateLimiterFuture::new(fut, connections_result)
+        let service = Rc::clone(&self.service);
+        let limiter = self.limiter.clone();
+        let local = Rc::clone(&self.local);
+        Box::pin(async move {
+            let (limiter, client) = match limiter.and_then(|l| l.identify(&req).map(|c| (l, c))) {
+                Some(limited) => limited,
+                None => {
+                    let res = service.call(req).await?;
+                    return Ok(res.map_body(|_, body| LimitedBody { body, slot: None }));
+                }
+            };
+
+            let group = RouteGroup::of(&req);
+            let (decision, slot) = match limiter.count(&client, group).await {
+                Ok((decision, slot)) => (Some(decision), slot),
+                Err(_) => match limiter.config.fallback {
+                    RateLimitFallback::Open => (None, None),
+                    RateLimitFallback::Closed => {
+                        return Err(CustomError::ServiceUnavailable(
+                            "rate limiter is unavailable".into(),
+                        )
+                        .into())
+                    }
+                    RateLimitFallback::Local => {
+                        let quota = limiter.quota(group);
+                        let key = format!("{}:{}", group.name(), client);
+                        (Some(local.borrow_mut().check(key, quota, Instant::now())), None)
+                    }
+                },
+            };
+
+            // Rejected requests never reach the handler.
+            if let Some(decision) = decision.as_ref().filter(|decision| !decision.allowed) {
+                metrics::rate_limit_rejection(group.name());
+                return Err(limiter.rejection(group, decision).into());
+            }
+
+            let mut res = service.call(req).await?;
+            if let Some(decision) = decision {
+                let headers = res.headers_mut();
+                for (name, value) in
+                    limit_headers(decision.limit, decision.remaining, decision.reset_secs())
+                {
+                    headers.insert(name, value);
+                }
+            }
+            Ok(res.map_body(|_, body| LimitedBody { body, slot }))
+        })
     }
 }
 
+/// Response body holding the stream slot of its client until the response is over.
 #[pin_project::pin_project]
-pub struct RateLimiterFuture<S, B>
-where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
-    B: MessageBody,
-{
+pub struct LimitedBody<B> {
     #[pin]
-    fut: S::Future,
-    _phantom: PhantomData<B>,
-    connections_result: CustomResult<u64>,
+    body: B,
+    slot: Option<StreamSlot>,
 }
 
-impl<S, B> RateLimiterFuture<S, B>
-where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
-    B: MessageBody,
-{
-    const CONNECTIONS_LIMIT: u64 = 5;
+impl<B: MessageBody> MessageBody for LimitedBody<B> {
+    type Error = B::Error;
 
-    pub fn new(fut: S::Future, connections_result: CustomResult<u64>) -> Self {
-        Self {
-            fut,
-            _phantom: Default::default(),
-            connections_result,
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        self.project().body.poll_next(cx)
+    }
+}
+
+/// Routes sharing a quota.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RouteGroup {
+    Read,
+    Write,
+    /// Board event streams, limited by the number of open ones.
+    Stream,
+}
+
+impl RouteGroup {
+    fn of(req: &ServiceRequest) -> Self {
+        let method = req.method();
+        if method == Method::GET
+            && (req.path().ends_with("/updates") || req.path().ends_with("/ws"))
+        {
+            Self::Stream
+        } else if method == Method::GET || method == Method::HEAD || method == Method::OPTIONS {
+            Self::Read
+        } else {
+            Self::Write
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Write => "write",
+            Self::Stream => "stream",
         }
     }
 }
 
-impl<S, B> Future for RateLimiterFuture<S, B>
-where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
-    B: MessageBody,
-{
-    type Output = Result<ServiceResponse<B>, actix_web::Error>;
+/// Finds client address. Behind a trusted proxy it's the last `X-Forwarded-For` entry
+/// not added by a trusted proxy, as entries on the left are set by the client itself.
+fn client_ip(req: &ServiceRequest, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+    if !trusted_proxies.contains(&peer) {
+        return Some(peer);
+    }
+
+    let forwarded: Vec<IpAddr> = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|address| address.trim().parse().ok())
+        .collect();
+    let client = forwarded
+        .iter()
+        .rev()
+        .find(|ip| !trusted_proxies.contains(ip));
+    Some(*client.or_else(|| forwarded.first()).unwrap_or(&peer))
+}
+
+const KEY_PREFIX: &str = "RATE_LIMIT";
+
+/// Counts requests in a window which starts with the first one.
+/// KEYS: counter. ARGV: limit, window ms. Returns allowed flag, remaining requests and reset ms.
+const FIXED_WINDOW_SCRIPT: &str = r"
+local limit, window = tonumber(ARGV[1]), tonumber(ARGV[2])
+local count = tonumber(redis.call('GET', KEYS[1]) or '0')
+if count >= limit then
+    local reset = redis.call('PTTL', KEYS[1])
+    return {0, 0, reset < 0 and window or reset}
+end
+count = redis.call('INCR', KEYS[1])
+if count == 1 then
+    redis.call('PEXPIRE', KEYS[1], window)
+end
+return {1, limit - count, redis.call('PTTL', KEYS[1])}
+";
+
+/// Keeps timestamps of requests made during the last window in a sorted set.
+/// KEYS: log. ARGV: limit, window ms, now ms, unique member. Returns like the fixed window.
+const SLIDING_LOG_SCRIPT: &str = r"
+local limit, window, now = tonumber(ARGV[1]), tonumber(ARGV[2]), tonumber(ARGV[3])
+redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', now - window)
+local count = redis.call('ZCARD', KEYS[1])
+local allowed = 0
+if count < limit then
+    redis.call('ZADD', KEYS[1], now, ARGV[4])
+    redis.call('PEXPIRE', KEYS[1], window)
+    count = count + 1
+    allowed = 1
+end
+local oldest = redis.call('ZRANGE', KEYS[1], 0, 0, 'WITHSCORES')
+local reset = math.max(tonumber(oldest[2]) + window - now, 0)
+return {allowed, math.max(limit - count, 0), reset}
+";
+
+/// GCRA: stores theoretical arrival time of the next request. Every request moves it by
+/// `window / limit`, a request is allowed while it stays within one window from now.
+/// KEYS: arrival time. ARGV: limit, window ms, now ms. Returns like the fixed window.
+const TOKEN_BUCKET_SCRIPT: &str = r"
+local limit, window, now = tonumber(ARGV[1]), tonumber(ARGV[2]), tonumber(ARGV[3])
+local interval = window / limit
+local tat = math.max(tonumber(redis.call('GET', KEYS[1])) or now, now)
+local new_tat = tat + interval
+if new_tat - window > now then
+    return {0, 0, math.ceil(new_tat - window - now)}
+end
+redis.call('SET', KEYS[1], new_tat, 'PX', math.ceil(new_tat - now))
+return {1, math.floor((now + window - new_tat) / interval), math.ceil(new_tat - now)}
+";
+
+/// Slots of open streams in a sorted set scored by opening time. Slots older than the TTL
+/// are dropped, so ones leaked by a crashed server don't count forever.
+/// KEYS: slots. ARGV: limit, now ms, slot id, TTL ms. Returns allowed flag and remaining slots.
+const OPEN_STREAM_SCRIPT: &str = r"
+local limit, now, ttl = tonumber(ARGV[1]), tonumber(ARGV[2]), tonumber(ARGV[4])
+redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', now - ttl)
+local count = redis.call('ZCARD', KEYS[1])
+if count >= limit then
+    return {0, 0}
+end
+redis.call('ZADD', KEYS[1], now, ARGV[3])
+redis.call('PEXPIRE', KEYS[1], ttl)
+return {1, limit - count - 1}
+";
+
+/// Longest wait for Redis before the fallback is used.
+const REDIS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long the fallback is used before Redis is tried again.
+const REDIS_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Fallback buckets kept by a worker before full ones are dropped.
+const MAX_LOCAL_BUCKETS: usize = 10_000;
+
+/// Streams open longer than this stop counting against the client.
+const STREAM_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Suggested delay before retrying a rejected stream, seconds.
+pub const STREAM_RETRY_AFTER: u64 = 5;
+
+/// Result of counting a request.
+#[derive(Debug, Clone)]
+pub struct Decision {
+    pub allowed: bool,
+    /// Requests allowed per window.
+    pub limit: u64,
+    /// Requests left until the limit is reached.
+    pub remaining: u64,
+    /// When rejected, time until the next request is allowed. Otherwise time until the quota
+    /// is restored, at least partly.
+    pub reset_after: Duration,
+}
+
+impl Decision {
+    /// `reset_after` rounded up to whole seconds, as sent to clients.
+    pub fn reset_secs(&self) -> u64 {
+        (self.reset_after.as_millis() as u64).div_ceil(1000)
+    }
+}
+
+/// `X-RateLimit-*` headers telling clients how many requests they have left and when
+/// the quota is restored, in seconds from now.
+pub fn limit_headers(limit: u64, remaining: u64, reset: u64) -> [(HeaderName, HeaderValue); 3] {
+    [
+        (HeaderName::from_static("x-ratelimit-limit"), limit.into()),
+        (
+            HeaderName::from_static("x-ratelimit-remaining"),
+            remaining.into(),
+        ),
+        (HeaderName::from_static("x-ratelimit-reset"), reset.into()),
+    ]
+}
+
+/// Checks requests of every client against the configured quotas.
+pub struct Limiter {
+    connection_manager: ConnectionManager,
+    config: RateLimitConfig,
+    auth: Arc<Auth>,
+    script: Script,
+    streams_script: Script,
+    /// Redis isn't asked until then after it failed.
+    retry_at: Mutex<Option<Instant>>,
+}
+
+impl Limiter {
+    pub fn new(
+        connection_manager: ConnectionManager,
+        config: RateLimitConfig,
+        auth: Arc<Auth>,
+    ) -> Self {
+        let script = match config.algorithm {
+            RateLimitAlgorithm::FixedWindow => FIXED_WINDOW_SCRIPT,
+            RateLimitAlgorithm::SlidingLog => SLIDING_LOG_SCRIPT,
+            RateLimitAlgorithm::TokenBucket => TOKEN_BUCKET_SCRIPT,
+        };
+        Self {
+            connection_manager,
+            config,
+            auth,
+            script: Script::new(script),
+            streams_script: Script::new(OPEN_STREAM_SCRIPT),
+            retry_at: Mutex::new(None),
+        }
+    }
+
+    /// Counts request in Redis. Fails without asking it for a while after it was unreachable.
+    async fn count(
+        self: &Arc<Self>,
+        client: &str,
+        group: RouteGroup,
+    ) -> CustomResult<(Decision, Option<StreamSlot>)> {
+        let retry_at = *self.retry_at.lock().unwrap_or_else(|e| e.into_inner());
+        if retry_at.is_some_and(|retry_at| Instant::now() < retry_at) {
+            return Err(CustomError::RedisError("Redis is unavailable".into()));
+        }
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let connections = match &self.connections_result {
-            Ok(c) => *c,
-            Err(e) => return Poll::Ready(Err(e.clone().into())),
+        let counted = async {
+            match group {
+                RouteGroup::Stream => self.open_stream(client).await,
+                _ => Ok((self.check(client, group).await?, None)),
+            }
+        };
+        let counted = metrics::timed("redis", "rate_limit", counted);
+        let result = match actix_web::rt::time::timeout(REDIS_TIMEOUT, counted).await {
+            Ok(result) => result,
+            Err(_) => Err(CustomError::RedisError("request timed out".into())),
         };
 
-        if connections > Self::CONNECTIONS_LIMIT {
-            return Poll::Ready(Err(CustomError::TooManyRequests {
-                actual: connections,
-                max: Self::CONNECTIONS_LIMIT,
+        let mut retry_at = self.retry_at.lock().unwrap_or_else(|e| e.into_inner());
+        match &result {
+            Ok(_) if retry_at.is_some() => {
+                *retry_at = None;
+                log::info!("Redis is reachable again, rate limiter fallback is off");
+            }
+            Err(CustomError::RedisError(e)) if retry_at.is_none() => {
+                *retry_at = Some(Instant::now() + REDIS_RETRY_INTERVAL);
+                metrics::rate_limit_fallback(self.config.fallback.name());
+                log::error!(
+                    "Rate limiter can't reach Redis, {:?} fallback takes over: {}",
+                    self.config.fallback,
+                    e
+                );
             }
-            .into()));
+            Err(CustomError::RedisError(_)) => {
+                *retry_at = Some(Instant::now() + REDIS_RETRY_INTERVAL);
+            }
+            _ => {}
+        }
+        result
+    }
+
+    /// Key the client is counted by: a known API key, an authenticated user or an address.
+    /// Allowlisted clients are not counted.
+    fn identify(&self, req: &ServiceRequest) -> Option<String> {
+        let ip = client_ip(req, &self.config.trusted_proxies);
+        if ip.is_some_and(|ip| self.config.allowlist.contains(&ip)) {
+            return None;
+        }
+
+        let api_key = req
+            .headers()
+            .get("x-api-key")
+            .and_then(|key| key.to_str().ok());
+        if let Some(api_key) = api_key {
+            // Position, so the key itself doesn't get into Redis.
+            if let Some(index) = self.config.api_keys.iter().position(|key| key == api_key) {
+                return Some(format!("key:{}", index));
+            }
+        }
+
+        let token = stream_token(req.headers(), req.query_string());
+        if let Ok(user_id) = token.and_then(|token| self.auth.verify(&token, TokenKind::Access)) {
+            return Some(format!("user:{}", user_id));
+        }
+
+        // Peer address is missing e.g. on Unix sockets, such clients share one quota.
+        Some(match ip {
+            Some(ip) => format!("ip:{}", ip),
+            None => "ip:unknown".into(),
+        })
+    }
+
+    fn quota(&self, group: RouteGroup) -> Quota {
+        match group {
+            RouteGroup::Write => self.config.write,
+            _ => self.config.read,
         }
+    }
+
+    /// Counts request of a client, rejected requests are not counted.
+    pub async fn check(&self, client: &str, group: RouteGroup) -> CustomResult<Decision> {
+        let quota = self.quota(group);
+        let key = format!(
+            "{}:{:?}:{}:{}",
+            KEY_PREFIX,
+            self.config.algorithm,
+            group.name(),
+            client
+        );
+        let now = Utc::now().timestamp_millis();
+        // Tells apart requests of the same millisecond in the sliding log.
+        let member = format!("{}-{}", now, rand::random::<u32>());
+
+        let (allowed, remaining, reset) = self
+            .script
+            .key(key)
+            .arg(quota.max_requests)
+            .arg(quota.window * 1000)
+            .arg(now)
+            .arg(member)
+            .invoke_async::<_, (u8, u64, u64)>(&mut self.connection_manager.clone())
+            .await?;
+
+        Ok(Decision {
+            allowed: allowed == 1,
+            limit: quota.max_requests,
+            remaining,
+            reset_after: Duration::from_millis(reset),
+        })
+    }
+
+    /// Takes a slot for a new event stream of the client. The slot is freed when dropped.
+    pub async fn open_stream(
+        self: &Arc<Self>,
+        client: &str,
+    ) -> CustomResult<(Decision, Option<StreamSlot>)> {
+        let key = format!("{}:streams:{}", KEY_PREFIX, client);
+        let id = format!("{:x}", rand::random::<u64>());
+        let (allowed, remaining) = self
+            .streams_script
+            .key(&key)
+            .arg(self.config.max_streams)
+            .arg(Utc::now().timestamp_millis())
+            .arg(&id)
+            .arg(STREAM_TTL.as_millis() as u64)
+            .invoke_async::<_, (u8, u64)>(&mut self.connection_manager.clone())
+            .await?;
 
-        let this = self.project();
-        let res = match futures::ready!(this.fut.poll(cx)) {
-            Ok(res) => res,
-            Err(e) => return Poll::Ready(Err(e)),
+        let decision = Decision {
+            allowed: allowed == 1,
+            limit: self.config.max_streams,
+            remaining,
+            reset_after: Duration::ZERO,
         };
-        Poll::Ready(Ok(res))
+        let slot = decision.allowed.then(|| StreamSlot {
+            limiter: Arc::clone(self),
+            key,
+            id,
+        });
+        Ok((decision, slot))
+    }
+
+    async fn close_stream(&self, key: &str, id: &str) -> CustomResult<()> {
+        redis::cmd("ZREM")
+            .arg(key)
+            .arg(id)
+            .query_async::<_, ()>(&mut self.connection_manager.clone())
+            .await?;
+        Ok(())
+    }
+
+    fn rejection(&self, group: RouteGroup, decision: &Decision) -> CustomError {
+        match group {
+            RouteGroup::Stream => CustomError::TooManyStreams {
+                max: decision.limit,
+            },
+            _ => CustomError::TooManyRequests {
+                max: decision.limit,
+                window: self.quota(group).window,
+                retry_after: decision.reset_secs(),
+            },
+        }
     }
 }
 
-const KEY_PREFIX: &str = "RATE_LIMIT_";
+/// In-process token buckets of one worker, used while Redis is unreachable.
+/// Streams are counted as reading requests meanwhile.
+#[derive(Default)]
+struct LocalBuckets {
+    buckets: HashMap<String, LocalBucket>,
+}
 
-async fn get_connections_count(
-    address: Option<SocketAddr>,
-    mut cm: ConnectionManager,
-) -> CustomResult<u64> {
-    let addr =
-        address.ok_or_else(|| CustomError::InternalError("Can't parse peer address".into()))?;
+struct LocalBucket {
+    tokens: f64,
+    updated: Instant,
+    /// When the bucket refills completely, after that it's the same as a new one.
+    full_at: Instant,
+}
 
-    let current_minute = Utc::now().minute();
-    let key = format!("{}:{}:{}", KEY_PREFIX, addr, current_minute);
+impl LocalBuckets {
+    fn check(&mut self, key: String, quota: Quota, now: Instant) -> Decision {
+        if self.buckets.len() >= MAX_LOCAL_BUCKETS {
+            self.buckets.retain(|_, bucket| bucket.full_at > now);
+        }
+
+        let capacity = quota.max_requests as f64;
+        let rate = capacity / quota.window as f64;
+        let bucket = self.buckets.entry(key).or_insert(LocalBucket {
+            tokens: capacity,
+            updated: now,
+            full_at: now,
+        });
+        let refilled = bucket.tokens + (now - bucket.updated).as_secs_f64() * rate;
+        bucket.tokens = refilled.min(capacity);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        bucket.full_at = now + Duration::from_secs_f64((capacity - bucket.tokens) / rate);
+        let reset_after = if allowed {
+            bucket.full_at - now
+        } else {
+            Duration::from_secs_f64((1.0 - bucket.tokens) / rate)
+        };
+
+        Decision {
+            allowed,
+            limit: quota.max_requests,
+            remaining: bucket.tokens as u64,
+            reset_after,
+        }
+    }
+}
+
+/// Open event stream counted against its client.
+pub struct StreamSlot {
+    limiter: Arc<Limiter>,
+    key: String,
+    id: String,
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        let limiter = Arc::clone(&self.limiter);
+        let key = std::mem::take(&mut self.key);
+        let id = std::mem::take(&mut self.id);
+        actix_web::rt::spawn(async move {
+            if let Err(e) = limiter.close_stream(&key, &id).await {
+                log::warn!("Can't free stream slot of {}: {}", key, e);
+            }
+        });
+    }
+}
 
-    let mut pipeline = redis::pipe();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let (connections_count, _) = pipeline
-        .atomic()
-        .incr(&key, 1)
-        .expire(&key, 60)
-        .query_async::<_, (u64, u64)>(&mut cm)
-        .await?;
+    const QUOTA: Quota = Quota {
+        max_requests: 3,
+        window: 3,
+    };
 
-    Ok(connections_count)
+    fn check(buckets: &mut LocalBuckets, key: &str, now: Instant) -> Decision {
+        buckets.check(key.into(), QUOTA, now)
+    }
+
+    #[test]
+    fn local_buckets_allow_burst_then_reject() {
+        let mut buckets = LocalBuckets::default();
+        let now = Instant::now();
+        let remaining: Vec<_> = (0..3)
+            .map(|_| check(&mut buckets, "alice", now))
+            .inspect(|decision| assert!(decision.allowed))
+            .map(|decision| decision.remaining)
+            .collect();
+        assert_eq!(remaining, [2, 1, 0]);
+
+        let rejected = check(&mut buckets, "alice", now);
+        assert!(!rejected.allowed);
+        assert_eq!(rejected.limit, 3);
+        assert_eq!(rejected.remaining, 0);
+        assert_eq!(rejected.reset_secs(), 1);
+        // Other clients have their own buckets.
+        assert!(check(&mut buckets, "bob", now).allowed);
+    }
+
+    #[test]
+    fn local_buckets_refill_over_time() {
+        let mut buckets = LocalBuckets::default();
+        let now = Instant::now();
+        for _ in 0..3 {
+            check(&mut buckets, "alice", now);
+        }
+        assert!(!check(&mut buckets, "alice", now + Duration::from_millis(900)).allowed);
+
+        // One token a second comes back.
+        let refilled = check(&mut buckets, "alice", now + Duration::from_secs(1));
+        assert!(refilled.allowed);
+        assert_eq!(refilled.remaining, 0);
+        assert_eq!(refilled.reset_secs(), 3);
+
+        // A bucket never holds more than the quota.
+        let later = now + Duration::from_secs(60);
+        assert_eq!(check(&mut buckets, "alice", later).remaining, 2);
+    }
+
+    #[test]
+    fn limit_headers_carry_numbers() {
+        let headers = limit_headers(60, 59, 2);
+        let headers: Vec<_> = headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.to_str().unwrap()))
+            .collect();
+        assert_eq!(
+            headers,
+            [
+                ("x-ratelimit-limit", "60"),
+                ("x-ratelimit-remaining", "59"),
+                ("x-ratelimit-reset", "2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn reset_is_rounded_up() {
+        let decision = Decision {
+            allowed: false,
+            limit: 1,
+            remaining: 0,
+            reset_after: Duration::from_millis(1001),
+        };
+        assert_eq!(decision.reset_secs(), 2);
+    }
 }
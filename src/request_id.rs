@@ -0,0 +1,120 @@
+use actix_web::dev::{MessageBody, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::InternalError;
+use actix_web::http::{HeaderMap, HeaderName, HeaderValue};
+use futures::future::LocalBoxFuture;
+use std::future::Future;
+use std::rc::Rc;
+use tracing::Instrument;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest id accepted from a client.
+const MAX_ID_LENGTH: usize = 64;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Id of the request being handled by the current task, if any.
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Runs future as a part of handling the request with the id, e.g. a WebSocket command.
+pub async fn scope<F: Future>(id: String, future: F) -> F::Output {
+    REQUEST_ID.scope(id, future).await
+}
+
+/// Takes id sent by the client, so a request can be traced through several services,
+/// or makes a new one. Ids of clients are limited to safe characters as they get into logs.
+fn request_id(req: &ServiceRequest) -> String {
+    let sent = req.headers().get(REQUEST_ID_HEADER);
+    let sent = sent.and_then(|value| value.to_str().ok()).filter(|id| {
+        let allowed = |c: char| c.is_ascii_alphanumeric() || "-_.:".contains(c);
+        !id.is_empty() && id.len() <= MAX_ID_LENGTH && id.chars().all(allowed)
+    });
+    match sent {
+        Some(id) => id.to_string(),
+        None => format!("{:016x}", rand::random::<u64>()),
+    }
+}
+
+/// Assigns an id to every request, or accepts one from `X-Request-Id`. The id is available
+/// through [`current`] while the request is handled, is put into the request's
+/// `X-Request-Id` for inner middlewares and is returned in the response's one.
+/// The request is traced as a span with the id, parent of handler and storage spans.
+#[derive(Clone, Default)]
+pub struct RequestId;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestId
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = S::Error;
+    type Transform = RequestIdMiddleware<S>;
+    type InitError = ();
+    type Future = futures::future::Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        futures::future::ready(Ok(RequestIdMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequestIdMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = S::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let id = request_id(&req);
+        insert_id(req.headers_mut(), &id);
+        let route = req.match_pattern().unwrap_or_else(|| "unmatched".into());
+        let span = tracing::info_span!(
+            "request",
+            otel.name = %format!("{} {}", req.method(), route),
+            http.method = %req.method(),
+            http.route = %route,
+            http.status_code = tracing::field::Empty,
+            request_id = %id,
+        );
+        let scoped_id = id.clone();
+        let handled = async move {
+            match service.call(req).await {
+                Ok(mut res) => {
+                    tracing::Span::current().record("http.status_code", res.status().as_u16());
+                    insert_id(res.headers_mut(), &id);
+                    Ok(res)
+                }
+                Err(e) => {
+                    // Errors of inner middlewares are rendered here, while the id is set.
+                    let mut response = e.error_response();
+                    tracing::Span::current().record("http.status_code", response.status().as_u16());
+                    insert_id(response.headers_mut(), &id);
+                    Err(InternalError::from_response(e.to_string(), response).into())
+                }
+            }
+        };
+        Box::pin(REQUEST_ID.scope(scoped_id, handled.instrument(span)))
+    }
+}
+
+fn insert_id(headers: &mut HeaderMap, id: &str) {
+    if let Ok(value) = HeaderValue::from_str(id) {
+        headers.insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+}
@@ -0,0 +1,257 @@
+use crate::db::{EventMsgReceiver, EventMsgResult};
+use crate::errors::{CustomError, CustomResult};
+use crate::events::StoredEvent;
+use crate::metrics;
+use crate::models::{MoveTask, Precondition, Task};
+use crate::request_id;
+use crate::tasks::Tasks;
+use crate::validation::FieldError;
+use actix::{Actor, ActorContext, ActorFutureExt, AsyncContext, StreamHandler, WrapFuture};
+use actix_web::ResponseError;
+use actix_web_actors::ws;
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// Command sent by a client. `id` is chosen by the client and comes back in the reply.
+#[derive(Deserialize, Debug)]
+pub struct Request {
+    pub id: String,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+/// `version`, if set, must be the current version of the task, like `If-Match` of the HTTP API.
+#[derive(Deserialize, Debug)]
+#[allow(clippy::enum_variant_names)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    CreateTask {
+        task: Task,
+    },
+    UpdateTask {
+        task_id: String,
+        task: Task,
+        version: Option<u64>,
+    },
+    MoveTask {
+        task_id: String,
+        #[serde(default)]
+        target: MoveTask,
+        version: Option<u64>,
+    },
+    DeleteTask {
+        task_id: String,
+        version: Option<u64>,
+    },
+}
+
+/// Message sent to a client.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Reply {
+    Event(StoredEvent),
+    /// Command succeeded. Carries the affected task.
+    Ack {
+        request_id: String,
+        task: Task,
+    },
+    /// Command or the connection failed. `request_id` is missing if request can't be parsed.
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+        status: u16,
+        /// Stable error code, see [`CustomError::code`].
+        code: &'static str,
+        message: String,
+        /// Problems with command fields, for `422` status.
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        errors: Vec<FieldError>,
+    },
+}
+
+impl Reply {
+    fn error(request_id: Option<String>, error: &CustomError) -> Self {
+        metrics::error(error.variant());
+        Self::Error {
+            request_id,
+            status: error.status_code().as_u16(),
+            code: error.code(),
+            message: error.detail(),
+            errors: error.field_errors().to_vec(),
+        }
+    }
+}
+
+/// WebSocket connection to a board: pushes board events and executes task commands.
+pub struct BoardSocket {
+    board_id: String,
+    user_id: ObjectId,
+    tasks: Arc<Tasks>,
+    events: Option<EventMsgReceiver>,
+    last_heartbeat: Instant,
+    /// Id of the HTTP request which opened the connection. Commands run under it,
+    /// so their logs and events are traced to the connection.
+    connection_id: Option<String>,
+    _subscriber: metrics::Subscriber,
+}
+
+impl BoardSocket {
+    pub fn new(
+        board_id: String,
+        user_id: ObjectId,
+        tasks: Arc<Tasks>,
+        events: EventMsgReceiver,
+    ) -> Self {
+        Self {
+            board_id,
+            user_id,
+            tasks,
+            events: Some(events),
+            last_heartbeat: Instant::now(),
+            connection_id: request_id::current(),
+            _subscriber: metrics::Subscriber::new("websocket"),
+        }
+    }
+
+    fn send(ctx: &mut ws::WebsocketContext<Self>, reply: &Reply) {
+        match serde_json::to_string(reply) {
+            Ok(text) => ctx.text(text),
+            Err(e) => log::error!("Can't serialize WebSocket reply: {}", e),
+        }
+    }
+
+    fn handle_request(&mut self, text: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let request = match serde_json::from_str::<Request>(text) {
+            Ok(request) => request,
+            Err(e) => {
+                let error = CustomError::BadRequest(format!("invalid request: {}", e));
+                return Self::send(ctx, &Reply::error(None, &error));
+            }
+        };
+
+        let tasks = Arc::clone(&self.tasks);
+        let board_id = self.board_id.clone();
+        let user_id = self.user_id;
+        let Request { id: request_id, command } = request;
+        let connection_id = self.connection_id.clone().unwrap_or_default();
+        let execution = request_id::scope(connection_id, async move {
+            execute(&tasks, &board_id, command, &user_id).await
+        });
+
+        // Waiting keeps commands in order they were sent: a drag can produce several moves of one task.
+        ctx.wait(execution.into_actor(self).map(move |result, _, ctx| {
+            let reply = match result {
+                Ok(task) => Reply::Ack { request_id, task },
+                Err(e) => Reply::error(Some(request_id), &e),
+            };
+            Self::send(ctx, &reply);
+        }));
+    }
+}
+
+async fn execute(
+    tasks: &Tasks,
+    board_id: &str,
+    command: Command,
+    user_id: &ObjectId,
+) -> CustomResult<Task> {
+    match command {
+        Command::CreateTask { task } => tasks.create_task(board_id, task, user_id).await,
+        Command::UpdateTask {
+            task_id,
+            task,
+            version,
+        } => {
+            let if_match = expected(version);
+            tasks.update_task(board_id, &task_id, task, &if_match, user_id).await
+        }
+        Command::MoveTask {
+            task_id,
+            target,
+            version,
+        } => {
+            let if_match = expected(version);
+            tasks.move_task(board_id, &task_id, target, &if_match, user_id).await
+        }
+        Command::DeleteTask { task_id, version } => {
+            let if_match = expected(version);
+            tasks.delete_task(board_id, &task_id, &if_match, user_id).await
+        }
+    }
+}
+
+fn expected(version: Option<u64>) -> Precondition {
+    Precondition(version.map(|version| vec![version]))
+}
+
+impl Actor for BoardSocket {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(events) = self.events.take() {
+            ctx.add_stream(tokio_stream::wrappers::ReceiverStream::new(events));
+        }
+
+        ctx.run_interval(HEARTBEAT_INTERVAL, |socket, ctx| {
+            if socket.last_heartbeat.elapsed() > CLIENT_TIMEOUT {
+                log::debug!("WebSocket client of board {} timed out", socket.board_id);
+                ctx.stop();
+            } else {
+                ctx.ping(b"");
+            }
+        });
+    }
+}
+
+/// Board events. The stream ends when the board is deleted, closing the connection.
+/// The connection is also closed once the user is removed from the board's members.
+impl StreamHandler<EventMsgResult> for BoardSocket {
+    fn handle(&mut self, event: EventMsgResult, ctx: &mut Self::Context) {
+        match event {
+            Ok(event) if event.event.revokes_access(&self.user_id) => {
+                let error = CustomError::Forbidden("no longer a member of the board".into());
+                Self::send(ctx, &Reply::error(None, &error));
+                ctx.close(Some(ws::CloseCode::Policy.into()));
+                ctx.stop();
+            }
+            Ok(event) => Self::send(ctx, &Reply::Event(event)),
+            Err(e) => {
+                Self::send(ctx, &Reply::error(None, &e));
+                ctx.stop();
+            }
+        }
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for BoardSocket {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(e) => {
+                log::warn!("WebSocket protocol error: {}", e);
+                return ctx.stop();
+            }
+        };
+
+        self.last_heartbeat = Instant::now();
+        match msg {
+            ws::Message::Text(text) => self.handle_request(&text, ctx),
+            ws::Message::Ping(payload) => ctx.pong(&payload),
+            ws::Message::Pong(_) => {}
+            ws::Message::Close(reason) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            ws::Message::Binary(_) | ws::Message::Continuation(_) => {
+                let error = CustomError::BadRequest("only text messages are supported".into());
+                Self::send(ctx, &Reply::error(None, &error));
+            }
+            ws::Message::Nop => {}
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use crate::db::{BoardsDatabase, TasksDatabase};
+use crate::errors::{CustomError, CustomResult};
+use crate::models::{Board, Role, Stage};
+use crate::validation::{Validate, Validator, MAX_STAGES};
+use mongodb::bson::oid::ObjectId;
+
+pub struct Stages {
+    boards: Box<dyn BoardsDatabase>,
+    tasks: Box<dyn TasksDatabase>,
+}
+
+impl Stages {
+    pub fn new(boards: Box<dyn BoardsDatabase>, tasks: Box<dyn TasksDatabase>) -> Self {
+        Self { boards, tasks }
+    }
+
+    /// Reads board checking that user may manage its stages.
+    async fn authorize(&self, board_id: &str, user_id: &ObjectId) -> CustomResult<Board> {
+        let board = self.boards.read_board(board_id).await?;
+        board.authorize(user_id, Role::Owner)?;
+        Ok(board)
+    }
+
+    pub async fn add_stage(
+        &self,
+        board_id: &str,
+        name: String,
+        user_id: &ObjectId,
+    ) -> CustomResult<Board> {
+        let mut board = self.authorize(board_id, user_id).await?;
+        let stage = Stage {
+            id: ObjectId::new().to_hex(),
+            name,
+        };
+        let mut validator = Validator::default();
+        stage.validate_fields(&mut validator);
+        if board.stages.len() >= MAX_STAGES {
+            validator.error("stages", format!("board may have at most {} stages", MAX_STAGES));
+        }
+        validator.finish()?;
+        board.stages.push(stage);
+        self.boards.update_board(board_id, board).await
+    }
+
+    pub async fn rename_stage(
+        &self,
+        board_id: &str,
+        stage_id: &str,
+        name: String,
+        user_id: &ObjectId,
+    ) -> CustomResult<Board> {
+        let mut board = self.authorize(board_id, user_id).await?;
+        let stage = board.stages.iter_mut().find(|stage| stage.id == stage_id);
+        let stage = stage.ok_or_else(|| stage_not_found(stage_id))?;
+        stage.name = name;
+        stage.validate()?;
+        self.boards.update_board(board_id, board).await
+    }
+
+    /// Reorders stages. `order` must contain every board's stage id exactly once.
+    pub async fn reorder_stages(
+        &self,
+        board_id: &str,
+        order: Vec<String>,
+        user_id: &ObjectId,
+    ) -> CustomResult<Board> {
+        let mut board = self.authorize(board_id, user_id).await?;
+        let mut reordered = Vec::with_capacity(order.len());
+        for stage_id in order.iter() {
+            let position = board.stages.iter().position(|stage| &stage.id == stage_id);
+            let position = position.ok_or_else(|| {
+                CustomError::BadRequest(format!("unknown or repeated stage: {}", stage_id))
+            })?;
+            reordered.push(board.stages.remove(position));
+        }
+
+        if !board.stages.is_empty() {
+            return Err(CustomError::BadRequest(
+                "new order must contain all stages".into(),
+            ));
+        }
+
+        board.stages = reordered;
+        self.boards.update_board(board_id, board).await
+    }
+
+    /// Deletes stage, moving its tasks to `move_to` stage or, if not set, to the first remaining one.
+    pub async fn delete_stage(
+        &self,
+        board_id: &str,
+        stage_id: &str,
+        move_to: Option<&str>,
+        user_id: &ObjectId,
+    ) -> CustomResult<Board> {
+        let mut board = self.authorize(board_id, user_id).await?;
+        let position = board.stages.iter().position(|stage| stage.id == stage_id);
+        board.stages.remove(position.ok_or_else(|| stage_not_found(stage_id))?);
+
+        let target = match move_to {
+            Some(target_id) => board.stage(target_id).ok_or_else(|| {
+                CustomError::BadRequest(format!("can't move tasks to stage: {}", target_id))
+            })?,
+            None => board
+                .stages
+                .first()
+                .ok_or_else(|| CustomError::BadRequest("can't delete the last stage".into()))?,
+        };
+
+        let target_id = target.id.clone();
+        self.tasks
+            .delete_stage(board_id, board, stage_id, &target_id)
+            .await
+    }
+}
+
+fn stage_not_found(id: &str) -> CustomError {
+    CustomError::NotFound(format!("stage with id: {}", id))
+}
@@ -1,33 +1,264 @@
-use crate::db::TasksDatabase;
-use crate::errors::CustomResult;
-use crate::models::Task;
+use crate::db::{BoardsDatabase, TasksDatabase};
+use crate::errors::{CustomError, CustomResult};
+use crate::models::{
+    Board, Cursor, ListQuery, MoveTask, Page, Patch, Precondition, Role, SortField, SortOrder,
+    Task, TaskFilter, TaskPatch,
+};
+use crate::rank;
+use crate::validation::{Validate, Validator};
+use mongodb::bson::oid::ObjectId;
 
 pub struct Tasks {
     db: Box<dyn TasksDatabase>,
+    boards: Box<dyn BoardsDatabase>,
 }
 
 impl Tasks {
-    pub fn new(db: Box<dyn TasksDatabase>) -> Self {
-        Self { db }
+    pub fn new(db: Box<dyn TasksDatabase>, boards: Box<dyn BoardsDatabase>) -> Self {
+        Self { db, boards }
     }
 
-    pub async fn create_task(&self, board_id: &str, task: Task) -> CustomResult<Task> {
+    /// Reads board checking that user has at least `required` role on it.
+    async fn authorize(
+        &self,
+        board_id: &str,
+        user_id: &ObjectId,
+        required: Role,
+    ) -> CustomResult<Board> {
+        let board = self.boards.read_board(board_id).await?;
+        board.authorize(user_id, required)?;
+        Ok(board)
+    }
+
+    /// Reads task making sure it belongs to the board, which access was checked for.
+    async fn read_board_task(&self, board: &Board, task_id: &str) -> CustomResult<Task> {
+        let task = self.db.read_task(&board_id(board), task_id).await?;
+        if task.board_id != board.id {
+            return Err(CustomError::NotFound(format!("task with id: {}", task_id)));
+        }
+        Ok(task)
+    }
+
+    /// Checks that task's stage exists on the board. Puts task to the first stage if none is set.
+    fn resolve_stage(board: &Board, task: &mut Task, validator: &mut Validator) {
+        if task.stage.is_empty() {
+            match board.stages.first() {
+                Some(first) => task.stage = first.id.clone(),
+                None => validator.error("stage", "board has no stages"),
+            }
+        } else if board.stage(&task.stage).is_none() {
+            validator.error("stage", format!("board has no stage: {}", task.stage));
+        }
+    }
+
+    /// Checks task's fields, resolving its stage.
+    fn validate(board: &Board, task: &mut Task) -> CustomResult<()> {
+        let mut validator = Validator::default();
+        task.validate_fields(&mut validator);
+        Self::resolve_stage(board, task, &mut validator);
+        validator.finish()
+    }
+
+    /// Returns the first task of the stage after (or, for descending order, before) `neighbour`
+    /// in manual order, skipping the task being moved.
+    async fn adjacent_task(
+        &self,
+        board_id: &str,
+        moved: &Task,
+        neighbour: &Task,
+        order: SortOrder,
+    ) -> CustomResult<Option<Task>> {
+        let query = ListQuery {
+            limit: Some(2),
+            cursor: Cursor::after(neighbour, SortField::Rank).map(|cursor| cursor.encode()),
+            sort: Some(SortField::Rank),
+            order,
+            name_prefix: None,
+        };
+        let filter = TaskFilter {
+            stage: Some(neighbour.stage.clone()),
+        };
+        let page = self.db.read_tasks(board_id, &query, &filter).await?;
+        Ok(page.items.into_iter().find(|task| task.id != moved.id))
+    }
+
+    /// Returns rank placing a task to the end of the stage.
+    async fn last_rank(&self, board_id: &str, stage: &str) -> CustomResult<String> {
+        let query = ListQuery {
+            limit: Some(1),
+            sort: Some(SortField::Rank),
+            order: SortOrder::Desc,
+            ..ListQuery::default()
+        };
+        let filter = TaskFilter {
+            stage: Some(stage.into()),
+        };
+        let page = self.db.read_tasks(board_id, &query, &filter).await?;
+        let last = page.items.first().map(|task| task.rank.as_str());
+        rank::between(last, None)
+    }
+
+    /// Reads a neighbour of task being moved and checks they are in the same stage.
+    async fn read_neighbour(&self, board_id: &str, task: &Task, id: &str) -> CustomResult<Task> {
+        let neighbour = self.db.read_task(board_id, id).await?;
+        let same_place = neighbour.board_id == task.board_id && neighbour.stage == task.stage;
+        if !same_place || neighbour.id == task.id {
+            return Err(CustomError::BadRequest(format!(
+                "task {} can't be a neighbour of the moved task",
+                id
+            )));
+        }
+        Ok(neighbour)
+    }
+
+    pub async fn create_task(
+        &self,
+        board_id: &str,
+        mut task: Task,
+        user_id: &ObjectId,
+    ) -> CustomResult<Task> {
+        let board = self.authorize(board_id, user_id, Role::Editor).await?;
+        Self::validate(&board, &mut task)?;
+        task.created_by = Some(*user_id);
+        task.rank = self.last_rank(board_id, &task.stage).await?;
         self.db.create_task(board_id, task).await
     }
 
-    pub async fn read_task(&self, board_id: &str, task_id: &str) -> CustomResult<Task> {
-        self.db.read_task(board_id, task_id).await
+    pub async fn read_task(
+        &self,
+        board_id: &str,
+        task_id: &str,
+        user_id: &ObjectId,
+    ) -> CustomResult<Task> {
+        let board = self.authorize(board_id, user_id, Role::Viewer).await?;
+        self.read_board_task(&board, task_id).await
+    }
+
+    pub async fn read_board_tasks(
+        &self,
+        board_id: &str,
+        query: &ListQuery,
+        filter: &TaskFilter,
+        user_id: &ObjectId,
+    ) -> CustomResult<Page<Task>> {
+        self.authorize(board_id, user_id, Role::Viewer).await?;
+        self.db.read_tasks(board_id, query, filter).await
+    }
+
+    pub async fn update_task(
+        &self,
+        board_id: &str,
+        task_id: &str,
+        mut task: Task,
+        if_match: &Precondition,
+        user_id: &ObjectId,
+    ) -> CustomResult<Task> {
+        let board = self.authorize(board_id, user_id, Role::Editor).await?;
+        Self::validate(&board, &mut task)?;
+        // Position is changed by moving only. Task put to another stage goes to its end.
+        let current = self.read_board_task(&board, task_id).await?;
+        if_match.check(current.version)?;
+        task.version = current.version;
+        task.created_by = current.created_by;
+        task.rank = if current.stage == task.stage {
+            current.rank
+        } else {
+            self.last_rank(board_id, &task.stage).await?
+        };
+        self.db.update_task(board_id, task_id, task).await
     }
 
-    pub async fn read_board_tasks(&self, board_id: &str) -> CustomResult<Vec<Task>> {
-        self.db.read_tasks(board_id).await
+    /// Changes only fields the patch touches. Task put to another stage goes to its end.
+    pub async fn patch_task(
+        &self,
+        board_id: &str,
+        task_id: &str,
+        patch: &Patch,
+        if_match: &Precondition,
+        user_id: &ObjectId,
+    ) -> CustomResult<Task> {
+        let board = self.authorize(board_id, user_id, Role::Editor).await?;
+        let current = self.read_board_task(&board, task_id).await?;
+        if_match.check(current.version)?;
+        let (mut patched, changed) = patch.apply(&current, TaskPatch::EDITABLE)?;
+        Self::validate(&board, &mut patched)?;
+        let mut task_patch = TaskPatch::new(patched.clone(), &changed);
+        if task_patch.stage.is_some() {
+            // Empty stage means the first one, which may be the current one.
+            if patched.stage == current.stage {
+                task_patch.stage = None;
+            } else {
+                task_patch.rank = Some(self.last_rank(board_id, &patched.stage).await?);
+                task_patch.stage = Some(patched.stage);
+            }
+        }
+        if task_patch.is_empty() {
+            return Ok(current);
+        }
+        self.db
+            .patch_task(board_id, task_id, current.version, &task_patch)
+            .await
     }
 
-    pub async fn update_task(&self, board_id: &str, task_id: &str, task: Task) -> CustomResult<Task> {
+    /// Moves task between `after` and `before` neighbours, possibly to another stage.
+    /// Only the moved task is updated.
+    pub async fn move_task(
+        &self,
+        board_id: &str,
+        task_id: &str,
+        target: MoveTask,
+        if_match: &Precondition,
+        user_id: &ObjectId,
+    ) -> CustomResult<Task> {
+        let board = self.authorize(board_id, user_id, Role::Editor).await?;
+        let mut task = self.read_board_task(&board, task_id).await?;
+        if_match.check(task.version)?;
+        if let Some(stage) = target.stage {
+            task.stage = stage;
+            let mut validator = Validator::default();
+            Self::resolve_stage(&board, &mut task, &mut validator);
+            validator.finish()?;
+        }
+
+        let after = match &target.after {
+            Some(id) => Some(self.read_neighbour(board_id, &task, id).await?),
+            None => None,
+        };
+        let before = match &target.before {
+            Some(id) => Some(self.read_neighbour(board_id, &task, id).await?),
+            None => None,
+        };
+
+        task.rank = match (after, before) {
+            (Some(after), Some(before)) => rank::between(Some(&after.rank), Some(&before.rank))?,
+            (Some(after), None) => {
+                let next = self.adjacent_task(board_id, &task, &after, SortOrder::Asc).await?;
+                rank::between(Some(&after.rank), next.as_ref().map(|t| t.rank.as_str()))?
+            }
+            (None, Some(before)) => {
+                let prev = self.adjacent_task(board_id, &task, &before, SortOrder::Desc).await?;
+                rank::between(prev.as_ref().map(|t| t.rank.as_str()), Some(&before.rank))?
+            }
+            (None, None) => self.last_rank(board_id, &task.stage).await?,
+        };
+
         self.db.update_task(board_id, task_id, task).await
     }
 
-    pub async fn delete_task(&self, board_id: &str, task_id: &str) -> CustomResult<Task> {
-        self.db.delete_task(board_id, task_id).await
+    pub async fn delete_task(
+        &self,
+        board_id: &str,
+        task_id: &str,
+        if_match: &Precondition,
+        user_id: &ObjectId,
+    ) -> CustomResult<Task> {
+        let board = self.authorize(board_id, user_id, Role::Editor).await?;
+        let current = self.read_board_task(&board, task_id).await?;
+        if_match.check(current.version)?;
+        self.db.delete_task(board_id, task_id, current.version).await
     }
 }
+
+fn board_id(board: &Board) -> String {
+    board.id.map(|id| id.to_hex()).unwrap_or_default()
+}
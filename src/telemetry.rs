@@ -0,0 +1,137 @@
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{Protocol, WithExportConfig};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use std::env;
+use std::error::Error;
+use tracing_subscriber::layer::SubscriberExt;
+
+const SERVICE_NAME: &str = "boards_back";
+
+/// Exports spans over OTLP/HTTP if `OTEL_EXPORTER_OTLP_ENDPOINT` or
+/// `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` is set, otherwise spans are discarded.
+/// Returns the provider, which must be shut down to send the remaining spans.
+pub fn init() -> Result<Option<SdkTracerProvider>, Box<dyn Error>> {
+    let configured = ["OTEL_EXPORTER_OTLP_ENDPOINT", "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"]
+        .iter()
+        .any(|name| env::var_os(name).is_some());
+    if !configured {
+        return Ok(None);
+    }
+
+    // Endpoint, headers and timeout are read by the exporter from the standard variables.
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_protocol(Protocol::HttpBinary)
+        .build()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build())
+        .build();
+    with_provider(&provider)?;
+    log::info!("Exporting traces over OTLP");
+    Ok(Some(provider))
+}
+
+/// Makes spans of `tracing` go to the provider.
+fn with_provider(provider: &SdkTracerProvider) -> Result<(), Box<dyn Error>> {
+    let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME));
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer))?;
+    Ok(())
+}
+
+/// Sends the spans which are still buffered.
+pub fn shutdown(provider: SdkTracerProvider) {
+    if let Err(e) = provider.shutdown() {
+        log::warn!("Can't export the remaining spans: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Auth;
+    use crate::config::AuthConfig;
+    use crate::db::cached::Cached;
+    use crate::db::measured::Measured;
+    use crate::db::memory::Memory;
+    use crate::handlers::read_board;
+    use crate::request_id::RequestId;
+    use crate::Services;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::{test, web, App};
+    use mongodb::bson::oid::ObjectId;
+    use opentelemetry::trace::SpanId;
+    use opentelemetry_sdk::trace::{InMemorySpanExporter, SpanData};
+    use std::sync::Arc;
+
+    fn attribute(span: &SpanData, key: &str) -> Option<String> {
+        let value = span.attributes.iter().find(|kv| kv.key.as_str() == key);
+        value.map(|kv| kv.value.to_string())
+    }
+
+    /// Finds the only span of the trace with the name and `db.system`.
+    fn find<'a>(spans: &'a [&SpanData], name: &str, system: Option<&str>) -> &'a SpanData {
+        let found: Vec<_> = spans
+            .iter()
+            .filter(|span| span.name == name && attribute(span, "db.system").as_deref() == system)
+            .collect();
+        assert_eq!(found.len(), 1, "spans {} of {:?}", name, system);
+        found[0]
+    }
+
+    #[actix_rt::test]
+    async fn traces_handler_storage_and_redis() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        with_provider(&provider).unwrap();
+
+        let auth = Arc::new(Auth::new(&AuthConfig {
+            jwt_secret: "secret of the tests, 32 bytes long".into(),
+            access_token_ttl: 900,
+            refresh_token_ttl: 3600,
+        }));
+        // Nothing listens on the port, so Redis calls fail but are still traced.
+        let redis_client = redis::Client::open("redis://127.0.0.1:1/").unwrap();
+        let db = Cached::new(Measured::new(Memory::new(16), "memory"), redis_client, 16);
+        let services = Services::new(db, &auth);
+        let tokens = auth.issue_tokens(&ObjectId::new()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .wrap(RequestId)
+                .service(read_board)
+                .app_data(web::Data::new(Arc::clone(&services.boards)))
+                .app_data(web::Data::new(Arc::clone(&auth))),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/boards/{}", ObjectId::new().to_hex()))
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", tokens.access_token)))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        provider.force_flush().unwrap();
+        let finished = exporter.get_finished_spans().unwrap();
+        let request = finished
+            .iter()
+            .find(|span| span.name == "GET /boards/{board_id}")
+            .expect("request span");
+        assert_eq!(request.parent_span_id, SpanId::INVALID);
+        let trace_id = request.span_context.trace_id();
+        let spans: Vec<_> = finished
+            .iter()
+            .filter(|span| span.span_context.trace_id() == trace_id)
+            .collect();
+
+        let handler = find(&spans, "read_board", None);
+        assert_eq!(handler.parent_span_id, request.span_context.span_id());
+        let storage = find(&spans, "read_board", Some("memory"));
+        assert_eq!(storage.parent_span_id, handler.span_context.span_id());
+        let redis = find(&spans, "cache_get", Some("redis"));
+        assert_eq!(redis.parent_span_id, handler.span_context.span_id());
+    }
+}
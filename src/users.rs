@@ -0,0 +1,70 @@
+use crate::auth::{self, Auth, TokenKind, Tokens};
+use crate::db::UsersDatabase;
+use crate::errors::{CustomError, CustomResult};
+use crate::models::{Credentials, User, UserInfo};
+use mongodb::bson::oid::ObjectId;
+use std::sync::Arc;
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+pub struct Users {
+    db: Box<dyn UsersDatabase>,
+    auth: Arc<Auth>,
+}
+
+impl Users {
+    pub fn new(db: Box<dyn UsersDatabase>, auth: Arc<Auth>) -> Self {
+        Self { db, auth }
+    }
+
+    pub async fn register(&self, credentials: Credentials) -> CustomResult<UserInfo> {
+        let username = credentials.username.trim().to_string();
+        if username.is_empty() {
+            return Err(CustomError::BadRequest("username must not be empty".into()));
+        }
+        if credentials.password.chars().count() < MIN_PASSWORD_LENGTH {
+            return Err(CustomError::BadRequest(format!(
+                "password must be at least {} characters long",
+                MIN_PASSWORD_LENGTH
+            )));
+        }
+
+        let user = User {
+            id: None,
+            username,
+            password_hash: auth::hash_password(credentials.password).await?,
+        };
+        Ok(self.db.create_user(user).await?.into())
+    }
+
+    pub async fn login(&self, credentials: Credentials) -> CustomResult<Tokens> {
+        // Same error for unknown user and wrong password, not to reveal registered names.
+        let invalid = || CustomError::Unauthorized("invalid username or password".into());
+        let user = match self.db.read_user_by_name(credentials.username.trim()).await {
+            Ok(user) => user,
+            Err(CustomError::NotFound(_)) => return Err(invalid()),
+            Err(e) => return Err(e),
+        };
+        if !auth::verify_password(credentials.password, user.password_hash).await? {
+            return Err(invalid());
+        }
+        let id = user.id.ok_or_else(|| CustomError::InternalError("user has no id".into()))?;
+        self.auth.issue_tokens(&id)
+    }
+
+    /// Issues new token pair for a valid refresh token of an existing user.
+    pub async fn refresh(&self, refresh_token: &str) -> CustomResult<Tokens> {
+        let id = self.auth.verify(refresh_token, TokenKind::Refresh)?;
+        match self.db.read_user(&id.to_hex()).await {
+            Ok(_) => self.auth.issue_tokens(&id),
+            Err(CustomError::NotFound(_)) => {
+                Err(CustomError::Unauthorized("user doesn't exist".into()))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn read_user(&self, id: &ObjectId) -> CustomResult<UserInfo> {
+        Ok(self.db.read_user(&id.to_hex()).await?.into())
+    }
+}
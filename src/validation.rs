@@ -0,0 +1,113 @@
+use crate::errors::{CustomError, CustomResult};
+use crate::models::{Board, Stage, Task};
+use serde::Serialize;
+
+pub const MAX_NAME_LENGTH: usize = 200;
+pub const MAX_DESCRIPTION_LENGTH: usize = 10_000;
+pub const MAX_STAGES: usize = 30;
+pub const MAX_STAGE_NAME_LENGTH: usize = 50;
+
+/// Problem with a field of the request body.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FieldError {
+    /// Path to the field, like `stages[1].name`.
+    pub field: String,
+    pub message: String,
+}
+
+/// Collects problems with fields of an entity, so all of them are reported at once.
+#[derive(Debug, Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn error(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        self.errors.push(FieldError {
+            field: field.into(),
+            message: message.into(),
+        });
+    }
+
+    /// Checks that value has something besides whitespace.
+    pub fn required(&mut self, field: &str, value: &str) {
+        if value.trim().is_empty() {
+            self.error(field, "must not be empty");
+        }
+    }
+
+    pub fn max_length(&mut self, field: &str, value: &str, max: usize) {
+        if value.chars().count() > max {
+            self.error(field, format!("must be at most {} characters long", max));
+        }
+    }
+
+    /// Fails with `Validation` error if any problem was found.
+    pub fn finish(self) -> CustomResult<()> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(CustomError::Validation(self.errors))
+        }
+    }
+}
+
+/// Entity coming from a client, which must be checked before it's stored.
+pub trait Validate {
+    fn validate_fields(&self, validator: &mut Validator);
+
+    fn validate(&self) -> CustomResult<()> {
+        let mut validator = Validator::default();
+        self.validate_fields(&mut validator);
+        validator.finish()
+    }
+}
+
+impl Validate for Board {
+    fn validate_fields(&self, validator: &mut Validator) {
+        validator.required("name", &self.name);
+        validator.max_length("name", &self.name, MAX_NAME_LENGTH);
+        validator.max_length("description", &self.description, MAX_DESCRIPTION_LENGTH);
+
+        if self.stages.is_empty() {
+            validator.error("stages", "board must have at least one stage");
+        }
+        if self.stages.len() > MAX_STAGES {
+            validator.error("stages", format!("board may have at most {} stages", MAX_STAGES));
+        }
+        for (i, stage) in self.stages.iter().enumerate() {
+            let field = format!("stages[{}]", i);
+            if self.stages[..i].iter().any(|other| other.id == stage.id) {
+                validator.error(format!("{}.id", field), "must differ from ids of other stages");
+            }
+            stage.validate_in(validator, &field);
+        }
+    }
+}
+
+impl Stage {
+    fn validate_in(&self, validator: &mut Validator, field: &str) {
+        let id_field = format!("{}.id", field);
+        validator.required(&id_field, &self.id);
+        validator.max_length(&id_field, &self.id, MAX_STAGE_NAME_LENGTH);
+        let name_field = format!("{}.name", field);
+        validator.required(&name_field, &self.name);
+        validator.max_length(&name_field, &self.name, MAX_STAGE_NAME_LENGTH);
+    }
+}
+
+impl Validate for Stage {
+    fn validate_fields(&self, validator: &mut Validator) {
+        validator.required("name", &self.name);
+        validator.max_length("name", &self.name, MAX_STAGE_NAME_LENGTH);
+    }
+}
+
+impl Validate for Task {
+    /// Stage is checked against the board's stages separately.
+    fn validate_fields(&self, validator: &mut Validator) {
+        validator.required("name", &self.name);
+        validator.max_length("name", &self.name, MAX_NAME_LENGTH);
+        validator.max_length("description", &self.description, MAX_DESCRIPTION_LENGTH);
+    }
+}